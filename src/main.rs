@@ -1,193 +1,4425 @@
-use actix_web::{web, App, HttpServer, HttpResponse, Responder};
-use serde::{Deserialize, Serialize};
-use std::sync::{Mutex, Arc};
-use std::collections::HashMap;
-use tokio;
-
-#[derive(Serialize, Clone)]
-struct Post {
-    id: u32,
-    title: String,
-    body: String,
-}
-
-#[derive(Serialize, Clone)]
-struct Comment {
-    id: u32,
-    post_id: u32,
-    text: String,
-}
-
-#[derive(Deserialize)]
-struct PostData {
-    title: String,
-    body: String,
-}
-
-#[derive(Deserialize)]
-struct CommentData {
-    post_id: u32,
-    text: String,
-}
-
-async fn index() -> impl Responder {
-    let html = r#"
-    <!DOCTYPE html>
-    <html>
-    <head>
-        <title>Blog Application</title>
-        <script src="https://cdn.tailwindcss.com"></script>
-    </head>
-    <body class="text-gray-900">
-        <div class="container mx-auto p-4">
-            <h1 class="text-4xl font-bold mb-4">Blog Posts</h1>
-            <div id="posts-list" class="space-y-4">Loading...</div>
-            <h2 class="text-2xl font-semibold mt-8 mb-2">Create New Post</h2>
-            <div class="space-y-2">
-                <input id="title" type="text" placeholder="Title" class="p-2 border border-gray-300 rounded w-full"/>
-                <textarea id="body" placeholder="Body" class="p-2 border border-gray-300 rounded w-full h-40"></textarea>
-                <button onclick="createPost()" class="px-4 py-2 bg-black text-white rounded">Create Post</button>
-            </div>
-        </div>
-        <script>
-            async function fetchPosts() {
-                let response = await fetch('/api/posts');
-                let posts = await response.json();
-                let postsList = document.getElementById('posts-list');
-                postsList.innerHTML = posts.map(post => `
-                    <div class="p-4 bg-white border border-black rounded cursor-pointer" onclick="viewPost(${post.id})">
-                        <h2 class="text-2xl font-bold">${post.title}</h2>
-                        <p class="mt-2">${post.body}</p>
-                    </div>
-                `).join('');
-            }
-
-            async function viewPost(postId) {
-                let response = await fetch(`/api/posts/${postId}`);
-                let post = await response.json();
-                let commentsResponse = await fetch(`/api/posts/${postId}/comments`);
-                let comments = await commentsResponse.json();
-                document.body.innerHTML = `
-                    <div class="container mx-auto p-4">
-                        <h1 class="text-4xl font-bold mb-4">${post.title}</h1>
-                        <p class="text-lg mb-4">${post.body}</p>
-                        <h2 class="text-2xl font-semibold mb-2">Comments</h2>
-                        <div id="comments-list" class="space-y-4">${comments.map(comment => `
-                            <div class="p-4 bg-white border border-black rounded">
-                                <p>${comment.text}</p>
-                            </div>
-                        `).join('')}</div>
-                        <h2 class="text-2xl font-semibold mt-8 mb-2">Add Comment</h2>
-                        <textarea id="comment-text" placeholder="Your comment" class="p-2 border border-gray-300 rounded w-full h-40"></textarea>
-                        <button onclick="addComment(${postId})" class="px-4 py-2 bg-black text-white rounded">Add Comment</button>
-                    </div>
-                `;
-            }
-
-            async function createPost() {
-                let title = document.getElementById('title').value;
-                let body = document.getElementById('body').value;
-                let response = await fetch('/api/posts', {
-                    method: 'POST',
-                    headers: {
-                        'Content-Type': 'application/json'
-                    },
-                    body: JSON.stringify({ title, body })
-                });
-                if (response.ok) {
-                    fetchPosts();
-                }
-            }
-
-            async function addComment(postId) {
-                let text = document.getElementById('comment-text').value;
-                let response = await fetch('/api/comments', {
-                    method: 'POST',
-                    headers: {
-                        'Content-Type': 'application/json'
-                    },
-                    body: JSON.stringify({ post_id: postId, text })
-                });
-                if (response.ok) {
-                    viewPost(postId);
-                }
-            }
-
-            window.onload = fetchPosts;
-        </script>
-    </body>
-    </html>
-    "#;
-    HttpResponse::Ok().content_type("text/html").body(html)
-}
-
-async fn get_posts(db: web::Data<Arc<Mutex<HashMap<u32, Post>>>>) -> impl Responder {
-    let db = db.lock().unwrap();
-    let posts: Vec<Post> = db.values().cloned().collect();
-    HttpResponse::Ok().json(posts)
-}
-
-async fn create_post(post: web::Json<PostData>, db: web::Data<Arc<Mutex<HashMap<u32, Post>>>>) -> impl Responder {
-    let mut db = db.lock().unwrap();
-    let id = (db.len() as u32) + 1;
-    let new_post = Post {
-        id,
-        title: post.title.clone(),
-        body: post.body.clone(),
-    };
-    db.insert(id, new_post);
-    HttpResponse::Created().finish()
-}
-
-async fn get_post(post_id: web::Path<u32>, db: web::Data<Arc<Mutex<HashMap<u32, Post>>>>) -> impl Responder {
-    let db = db.lock().unwrap();
-    if let Some(post) = db.get(&post_id.into_inner()) {
-        HttpResponse::Ok().json(post.clone())
-    } else {
-        HttpResponse::NotFound().finish()
-    }
-}
-
-async fn get_comments(post_id: web::Path<u32>, comments_db: web::Data<Arc<Mutex<HashMap<u32, Vec<Comment>>>>>) -> impl Responder {
-    let comments_db = comments_db.lock().unwrap();
-    if let Some(comments) = comments_db.get(&post_id.into_inner()) {
-        HttpResponse::Ok().json(comments.clone())
-    } else {
-        HttpResponse::Ok().json(Vec::<Comment>::new())
-    }
-}
-
-async fn create_comment(comment: web::Json<CommentData>, comments_db: web::Data<Arc<Mutex<HashMap<u32, Vec<Comment>>>>>) -> impl Responder {
-    let mut comments_db = comments_db.lock().unwrap();
-    let post_id = comment.post_id;
-    let new_comment = Comment {
-        id: comments_db.get(&post_id).map_or(1, |comments| comments.len() as u32 + 1),
-        post_id,
-        text: comment.text.clone(),
-    };
-    comments_db.entry(post_id)
-        .or_insert_with(Vec::new)
-        .push(new_comment);
-    HttpResponse::Created().finish()
-}
-
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    let posts_db: Arc<Mutex<HashMap<u32, Post>>> = Arc::new(Mutex::new(HashMap::new()));
-    let comments_db: Arc<Mutex<HashMap<u32, Vec<Comment>>>> = Arc::new(Mutex::new(HashMap::new()));
-
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(posts_db.clone()))
-            .app_data(web::Data::new(comments_db.clone()))
-            .route("/", web::get().to(index))
-            .route("/api/posts", web::get().to(get_posts))
-            .route("/api/posts", web::post().to(create_post))
-            .route("/api/posts/{id}", web::get().to(get_post))
-            .route("/api/posts/{id}/comments", web::get().to(get_comments))
-            .route("/api/comments", web::post().to(create_comment))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
-}
\ No newline at end of file
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Condition, DefaultHeaders, Next};
+use actix_web::{web, App, FromRequest, Handler, HttpMessage, HttpRequest, HttpServer, HttpResponse, Responder};
+use futures_util::future::LocalBoxFuture;
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use icu_collator::options::CollatorOptions;
+use icu_collator::{Collator, CollatorBorrowed};
+use icu_locale::Locale;
+use lettre::message::{header as mail_header, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use pulldown_cmark::{CowStr, Event, HeadingLevel, Parser, Tag, TagEnd};
+use rand::seq::SliceRandom;
+use rand::RngCore;
+use schemars::generate::SchemaSettings;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use tokio::sync::mpsc;
+
+type PostsDb = Arc<Mutex<HashMap<u32, Post>>>;
+type CommentsDb = Arc<Mutex<HashMap<u32, Vec<Comment>>>>;
+type SimilarityDb = Arc<Mutex<SimilarityIndex>>;
+type SearchDb = Arc<Mutex<SearchIndex>>;
+type RecentHashesDb = Arc<Mutex<RecentHashes>>;
+type RecentNoncesDb = Arc<Mutex<RecentNonces>>;
+type SlugRedirectsDb = Arc<Mutex<HashMap<String, u32>>>;
+type SnapshotsDb = Arc<Mutex<VecDeque<Snapshot>>>;
+type TrafficDb = Arc<Mutex<TrafficStats>>;
+type LatencyDb = Arc<Mutex<LatencyWindows>>;
+type DeletedPostsDb = Arc<Mutex<HashMap<u32, DeletedPost>>>;
+type SubscribersDb = Arc<Mutex<BTreeSet<String>>>;
+type ViewsDb = Arc<Mutex<HashMap<u32, u64>>>;
+type CommentIdsDb = Arc<Mutex<HashMap<u32, u32>>>;
+type LastPostIdDb = Arc<Mutex<u32>>;
+
+const CSRF_COOKIE: &str = "csrf_token";
+const MAX_TITLE_LENGTH: usize = 200;
+const MAX_COMMENT_LENGTH: usize = 10_000;
+const MAX_AUTHOR_LENGTH: usize = 100;
+const MAX_NONCE_LENGTH: usize = 128;
+const MAX_REMEMBERED_NONCES: usize = 10_000;
+const FORM_TOKEN_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+const CONFIRMATION_RESEND_SECS: u64 = 60 * 60;
+const DEFAULT_LATEST_LIMIT: usize = 10;
+const MAX_LATEST_LIMIT: usize = 50;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LENGTH: usize = 50;
+const DEFAULT_TAG_GROUPS: usize = 10;
+const DEFAULT_STOPWORDS: &str = "about above after again against all and any are because been before being below between both but can could did does doing down during each few for from further had has have having her here hers herself him himself his how into its itself just more most not now off once only other our ours out over own same she should some such than that the their theirs them then there these they this those through too under until very was were what when where which while who whom why will with would you your yours";
+const DEFAULT_POSTS_PER_TAG: usize = 5;
+const FEED_TITLE: &str = "Blog Application";
+const FEED_ITEMS: usize = 20;
+const MAX_JSON_BODY_BYTES: usize = 2 * 1024 * 1024;
+const MAX_IMPORT_LINE_BYTES: usize = 1024 * 1024;
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+const MAX_REPORTED_IMPORT_ERRORS: usize = 1000;
+const QUEUE_STATUS_RETENTION: usize = 10_000;
+const DEFAULT_UNAVAILABLE_JSON_TEMPLATE: &str = r#"{"error": "{message}", "status": {status}, "retry_after": {retry_after}}"#;
+const DEFAULT_UNAVAILABLE_HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>{status}</title>
+    <script src="https://cdn.tailwindcss.com"></script>
+</head>
+<body class="text-gray-900">
+    <div class="container mx-auto p-4">
+        <h1 class="text-4xl font-bold mb-4">{message}</h1>
+        <p class="text-lg">Please try again in {retry_after} seconds.</p>
+    </div>
+</body>
+</html>
+"#;
+
+#[derive(Clone)]
+enum PostSelection {
+    None,
+    All,
+    Only(HashSet<u32>),
+}
+
+impl PostSelection {
+    fn from_env(name: &str) -> Result<PostSelection, String> {
+        match env_string(name).as_deref() {
+            None => Ok(PostSelection::None),
+            Some("*") => Ok(PostSelection::All),
+            Some(ids) => ids
+                .split(',')
+                .map(|id| id.trim().parse::<u32>().map_err(|_| format!("{} contains an invalid post id {:?}", name, id)))
+                .collect::<Result<HashSet<u32>, String>>()
+                .map(PostSelection::Only),
+        }
+    }
+
+    fn contains(&self, post_id: u32) -> bool {
+        match self {
+            PostSelection::None => false,
+            PostSelection::All => true,
+            PostSelection::Only(ids) => ids.contains(&post_id),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DedupMode {
+    Off,
+    Reject,
+    Flag,
+}
+
+impl DedupMode {
+    fn from_env(name: &str) -> Result<DedupMode, String> {
+        match env_string(name).as_deref() {
+            None | Some("off") => Ok(DedupMode::Off),
+            Some("reject") => Ok(DedupMode::Reject),
+            Some("flag") => Ok(DedupMode::Flag),
+            Some(other) => Err(format!("{} must be one of off, reject, flag, got {:?}", name, other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ExcerptMode {
+    Chars,
+    Paragraph,
+}
+
+impl ExcerptMode {
+    fn from_env(name: &str) -> Result<ExcerptMode, String> {
+        match env_string(name).as_deref() {
+            None | Some("chars") => Ok(ExcerptMode::Chars),
+            Some("paragraph") => Ok(ExcerptMode::Paragraph),
+            Some(other) => Err(format!("{} must be chars or paragraph, got {:?}", name, other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum HtmlPolicy {
+    Allow,
+    Reject,
+    Strip,
+}
+
+impl HtmlPolicy {
+    fn from_env(name: &str) -> Result<HtmlPolicy, String> {
+        match env_string(name).as_deref() {
+            None | Some("allow") => Ok(HtmlPolicy::Allow),
+            Some("reject") => Ok(HtmlPolicy::Reject),
+            Some("strip") => Ok(HtmlPolicy::Strip),
+            Some(other) => Err(format!("{} must be one of allow, reject, strip, got {:?}", name, other)),
+        }
+    }
+
+    fn apply(self, field: &str, text: String) -> Result<String, String> {
+        if self == HtmlPolicy::Allow {
+            return Ok(text);
+        }
+        let (stripped, found) = strip_html_tags(&text);
+        match (found, self) {
+            (false, _) => Ok(text),
+            (true, HtmlPolicy::Reject) => Err(format!("{} must not contain HTML tags", field)),
+            (true, _) => Ok(stripped),
+        }
+    }
+}
+
+// Runs the text through the sanitizer with every tag disallowed. Ampersands are escaped first so
+// entities are kept as typed, and the serializer's escaping is undone afterwards to get plain text.
+fn strip_html_tags(text: &str) -> (String, bool) {
+    let cleaned = ammonia::Builder::empty().clean(&text.replace('&', "&amp;")).to_string();
+    let stripped = cleaned.replace("&lt;", "<").replace("&gt;", ">").replace("&nbsp;", "\u{a0}").replace("&amp;", "&");
+    let found = stripped != text.replace("\r\n", "\n").replace('\r', "\n");
+    (stripped, found)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SmtpTls {
+    StartTls,
+    Tls,
+    None,
+}
+
+#[derive(Clone)]
+struct SmtpConfig {
+    host: String,
+    port: u16,
+    tls: SmtpTls,
+    credentials: Option<(String, String)>,
+    from: Mailbox,
+}
+
+impl SmtpConfig {
+    fn from_env() -> Result<Option<SmtpConfig>, String> {
+        let Some(host) = env_string("SMTP_HOST") else {
+            return Ok(None);
+        };
+        let tls = match env_string("SMTP_TLS").as_deref() {
+            None | Some("starttls") => SmtpTls::StartTls,
+            Some("tls") => SmtpTls::Tls,
+            Some("none") => SmtpTls::None,
+            Some(other) => return Err(format!("SMTP_TLS must be one of starttls, tls, none, got {:?}", other)),
+        };
+        let credentials = match (env_string("SMTP_USERNAME"), env_string("SMTP_PASSWORD")) {
+            (Some(username), Some(password)) => Some((username, password)),
+            (None, None) => None,
+            _ => return Err("SMTP_USERNAME and SMTP_PASSWORD must be set together".to_string()),
+        };
+        let from = env_string("SMTP_FROM").ok_or("SMTP_FROM is required when SMTP_HOST is set")?;
+        Ok(Some(SmtpConfig {
+            host,
+            port: env_parse("SMTP_PORT", if tls == SmtpTls::Tls { 465 } else { 587 })?,
+            tls,
+            credentials,
+            from: from.parse().map_err(|_| format!("SMTP_FROM is not a valid mailbox: {:?}", from))?,
+        }))
+    }
+
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+        let builder = match self.tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host).map_err(|e| e.to_string())?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.host).map_err(|e| e.to_string())?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host),
+        };
+        let builder = builder.port(self.port);
+        Ok(match &self.credentials {
+            Some((username, password)) => builder.credentials(Credentials::new(username.clone(), password.clone())),
+            None => builder,
+        }
+        .build())
+    }
+}
+
+#[derive(Clone)]
+struct Config {
+    csrf_protection: bool,
+    csrf_secret: Vec<u8>,
+    admin_token: Option<String>,
+    default_per_page: usize,
+    max_per_page: usize,
+    comment_queue_capacity: usize,
+    queued_comment_posts: PostSelection,
+    similar_posts_limit: usize,
+    inline_replies: usize,
+    draft_max_age_days: Option<u64>,
+    draft_prune_interval_secs: u64,
+    draft_prune_dry_run: bool,
+    unavailable_json_template: String,
+    unavailable_html_template: String,
+    title_collator: Option<Arc<CollatorBorrowed<'static>>>,
+    comment_dedup: DedupMode,
+    comment_dedup_window_secs: u64,
+    comment_dedup_capacity: usize,
+    dev_mode: bool,
+    lenient_json: bool,
+    bind_addr: SocketAddr,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    redirect_http: bool,
+    http_port: u16,
+    search_fallback_scan: bool,
+    security_headers: Vec<(&'static str, String)>,
+    comments_close_after_days: Option<u64>,
+    path_prefix: String,
+    trusted_proxies: HashSet<IpAddr>,
+    lenient_import_utf8: bool,
+    max_snapshots: usize,
+    comment_nonce_window_secs: u64,
+    comment_min_seconds: Option<u64>,
+    response_envelope: bool,
+    auto_tagging: bool,
+    auto_tag_limit: usize,
+    auto_tag_stopwords: HashSet<String>,
+    hsts: Option<String>,
+    moderate_comments: bool,
+    html_in_text: HtmlPolicy,
+    request_id_header: Option<String>,
+    excerpt_mode: ExcerptMode,
+    excerpt_length: usize,
+    latency_window_secs: u64,
+    latency_max_samples: usize,
+    reject_duplicate_posts: bool,
+    base_url: Option<String>,
+    empty_index_message: String,
+    smtp: Option<SmtpConfig>,
+    body_read_timeout: Option<Duration>,
+    import_read_timeout: Option<Duration>,
+    linkify_comments: bool,
+    comment_max_links: Option<usize>,
+    flag_excess_links: bool,
+}
+
+impl Config {
+    fn from_env() -> Result<Config, String> {
+        let csrf_secret = match std::env::var("CSRF_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => {
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        let default_per_page = env_parse("DEFAULT_PER_PAGE", 20)?;
+        let max_per_page = env_parse("MAX_PER_PAGE", 100)?;
+        if default_per_page == 0 || default_per_page > max_per_page {
+            return Err(format!(
+                "DEFAULT_PER_PAGE ({}) must be between 1 and MAX_PER_PAGE ({})",
+                default_per_page, max_per_page
+            ));
+        }
+        let bind_addr = env_parse("BIND_ADDR", SocketAddr::from(([127, 0, 0, 1], 8080)))?;
+        let (tls_cert, tls_key) = (env_string("TLS_CERT"), env_string("TLS_KEY"));
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err("TLS_CERT and TLS_KEY must be set together".to_string());
+        }
+        let redirect_http = env_flag("REDIRECT_HTTP", false)?;
+        if redirect_http && tls_cert.is_none() {
+            return Err("REDIRECT_HTTP requires TLS_CERT and TLS_KEY".to_string());
+        }
+        let http_port = env_parse("HTTP_PORT", 80)?;
+        let hsts_max_age: u64 = env_parse("HSTS_MAX_AGE", 31_536_000)?;
+        let hsts_include_subdomains = env_flag("HSTS_INCLUDE_SUBDOMAINS", false)?;
+        let hsts_preload = env_flag("HSTS_PRELOAD", false)?;
+        if hsts_preload && (!hsts_include_subdomains || hsts_max_age < 31_536_000) {
+            return Err("HSTS_PRELOAD requires HSTS_INCLUDE_SUBDOMAINS and an HSTS_MAX_AGE of at least 31536000".to_string());
+        }
+        let mut hsts = format!("max-age={}", hsts_max_age);
+        if hsts_include_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+        if hsts_preload {
+            hsts.push_str("; preload");
+        }
+        if redirect_http && http_port == bind_addr.port() {
+            return Err(format!("HTTP_PORT ({}) must differ from the BIND_ADDR port", http_port));
+        }
+        Ok(Config {
+            csrf_protection: env_flag("CSRF_PROTECTION", true)?,
+            csrf_secret,
+            admin_token: env_string("ADMIN_TOKEN"),
+            default_per_page,
+            max_per_page,
+            comment_queue_capacity: env_parse("COMMENT_QUEUE_CAPACITY", 1000)?.max(1),
+            queued_comment_posts: PostSelection::from_env("COMMENT_QUEUE_POSTS")?,
+            similar_posts_limit: env_parse("SIMILAR_POSTS_LIMIT", 5)?,
+            inline_replies: env_parse("INLINE_REPLIES", 3)?,
+            draft_max_age_days: env_string("DRAFT_MAX_AGE_DAYS")
+                .map(|days| days.parse().map_err(|_| format!("DRAFT_MAX_AGE_DAYS has an invalid value {:?}", days)))
+                .transpose()?,
+            draft_prune_interval_secs: env_parse("DRAFT_PRUNE_INTERVAL_SECS", 3600)?.max(1),
+            draft_prune_dry_run: env_flag("DRAFT_PRUNE_DRY_RUN", true)?,
+            unavailable_json_template: env_file("UNAVAILABLE_JSON_TEMPLATE")?
+                .unwrap_or_else(|| DEFAULT_UNAVAILABLE_JSON_TEMPLATE.to_string()),
+            unavailable_html_template: env_file("UNAVAILABLE_HTML_TEMPLATE")?
+                .unwrap_or_else(|| DEFAULT_UNAVAILABLE_HTML_TEMPLATE.to_string()),
+            title_collator: env_string("SORT_LOCALE").map(|locale| title_collator(&locale)).transpose()?,
+            comment_dedup: DedupMode::from_env("COMMENT_DEDUP")?,
+            comment_dedup_window_secs: env_parse("COMMENT_DEDUP_WINDOW_SECS", 3600)?,
+            comment_dedup_capacity: env_parse("COMMENT_DEDUP_CAPACITY", 10_000)?,
+            dev_mode: env_flag("DEV_MODE", false)?,
+            lenient_json: match env_string("JSON_PARSING").as_deref() {
+                None | Some("strict") => false,
+                Some("lenient") => true,
+                Some(other) => return Err(format!("JSON_PARSING must be strict or lenient, got {:?}", other)),
+            },
+            bind_addr,
+            tls_cert,
+            tls_key,
+            redirect_http,
+            http_port,
+            search_fallback_scan: env_flag("SEARCH_FALLBACK_SCAN", true)?,
+            security_headers: security_headers()?,
+            path_prefix: path_prefix()?,
+            trusted_proxies: env_string("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| proxy.parse().map_err(|_| format!("TRUSTED_PROXIES contains an invalid address {:?}", proxy)))
+                .collect::<Result<_, _>>()?,
+            max_snapshots: env_parse("MAX_SNAPSHOTS", 5)?,
+            comment_nonce_window_secs: env_parse("COMMENT_NONCE_WINDOW_SECS", 600)?,
+            response_envelope: env_flag("RESPONSE_ENVELOPE", false)?,
+            hsts: env_flag("HSTS", true)?.then_some(hsts),
+            moderate_comments: env_flag("MODERATE_COMMENTS", false)?,
+            html_in_text: HtmlPolicy::from_env("HTML_IN_TEXT")?,
+            request_id_header: match std::env::var("REQUEST_ID_HEADER") {
+                Ok(header) if header.is_empty() => None,
+                Ok(header) => {
+                    HeaderName::from_bytes(header.as_bytes()).map_err(|_| format!("REQUEST_ID_HEADER is not a valid header name: {:?}", header))?;
+                    Some(header)
+                }
+                Err(_) => Some("X-Request-Id".to_string()),
+            },
+            excerpt_mode: ExcerptMode::from_env("EXCERPT")?,
+            excerpt_length: env_parse("EXCERPT_LENGTH", 200)?.max(1),
+            latency_window_secs: env_parse("LATENCY_WINDOW_SECS", 300)?.max(1),
+            latency_max_samples: env_parse("LATENCY_MAX_SAMPLES", 10_000)?.max(1),
+            base_url: match env_string("BASE_URL") {
+                Some(url) if url.starts_with("http://") || url.starts_with("https://") => Some(url.trim_end_matches('/').to_string()),
+                Some(url) => return Err(format!("BASE_URL must start with http:// or https://, got {:?}", url)),
+                None => None,
+            },
+            smtp: SmtpConfig::from_env()?,
+            linkify_comments: env_flag("COMMENT_LINKIFY", true)?,
+            comment_max_links: env_string("COMMENT_MAX_LINKS")
+                .map(|max| max.parse().map_err(|_| format!("COMMENT_MAX_LINKS has an invalid value {:?}", max)))
+                .transpose()?,
+            flag_excess_links: match env_string("COMMENT_EXCESS_LINKS").as_deref() {
+                None | Some("reject") => false,
+                Some("flag") => true,
+                Some(other) => return Err(format!("COMMENT_EXCESS_LINKS must be reject or flag, got {:?}", other)),
+            },
+            body_read_timeout: Some(Duration::from_secs(env_parse("BODY_READ_TIMEOUT_SECS", 30)?)).filter(|timeout| !timeout.is_zero()),
+            import_read_timeout: Some(Duration::from_secs(env_parse("IMPORT_READ_TIMEOUT_SECS", 600)?)).filter(|timeout| !timeout.is_zero()),
+            empty_index_message: env_string("EMPTY_INDEX_MESSAGE").unwrap_or_else(|| "No posts yet — create the first one!".to_string()),
+            reject_duplicate_posts: match env_string("DUPLICATE_POSTS").as_deref() {
+                None | Some("allow") => false,
+                Some("reject") => true,
+                Some(other) => return Err(format!("DUPLICATE_POSTS must be allow or reject, got {:?}", other)),
+            },
+            auto_tagging: env_flag("AUTO_TAGGING", false)?,
+            auto_tag_limit: env_parse("AUTO_TAG_LIMIT", 3)?.min(MAX_TAGS),
+            auto_tag_stopwords: env_file("AUTO_TAG_STOPWORDS")?
+                .as_deref()
+                .unwrap_or(DEFAULT_STOPWORDS)
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|word| !word.is_empty())
+                .map(str::to_lowercase)
+                .collect(),
+            comment_min_seconds: env_string("COMMENT_MIN_SECONDS")
+                .map(|secs| secs.parse().map_err(|_| format!("COMMENT_MIN_SECONDS has an invalid value {:?}", secs)))
+                .transpose()?,
+            lenient_import_utf8: match env_string("IMPORT_UTF8").as_deref() {
+                None | Some("strict") => false,
+                Some("lenient") => true,
+                Some(other) => return Err(format!("IMPORT_UTF8 must be strict or lenient, got {:?}", other)),
+            },
+            comments_close_after_days: env_string("COMMENTS_CLOSE_AFTER_DAYS")
+                .map(|days| days.parse().map_err(|_| format!("COMMENTS_CLOSE_AFTER_DAYS has an invalid value {:?}", days)))
+                .transpose()?,
+        })
+    }
+}
+
+fn title_collator(locale: &str) -> Result<Arc<CollatorBorrowed<'static>>, String> {
+    let locale: Locale = locale.parse().map_err(|e| format!("SORT_LOCALE {:?} is not a valid locale: {}", locale, e))?;
+    Collator::try_new((&locale).into(), CollatorOptions::default())
+        .map(Arc::new)
+        .map_err(|e| format!("SORT_LOCALE {}: no collation data: {}", locale, e))
+}
+
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| format!("{} has an invalid value {:?}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_file(name: &str) -> Result<Option<String>, String> {
+    env_string(name)
+        .map(|path| std::fs::read_to_string(&path).map_err(|e| format!("{}: cannot read {}: {}", name, path, e)))
+        .transpose()
+}
+
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; script-src 'self' 'unsafe-inline' https://cdn.tailwindcss.com; style-src 'self' 'unsafe-inline'; img-src 'self' https: data:; frame-ancestors 'none'";
+
+fn security_headers() -> Result<Vec<(&'static str, String)>, String> {
+    let settings = [
+        ("CONTENT_SECURITY_POLICY", "Content-Security-Policy", DEFAULT_CONTENT_SECURITY_POLICY),
+        ("X_CONTENT_TYPE_OPTIONS", "X-Content-Type-Options", "nosniff"),
+        ("X_FRAME_OPTIONS", "X-Frame-Options", "DENY"),
+        ("REFERRER_POLICY", "Referrer-Policy", "strict-origin-when-cross-origin"),
+    ];
+    let mut headers = Vec::new();
+    for (variable, header, default) in settings {
+        let value = std::env::var(variable).unwrap_or_else(|_| default.to_string());
+        if value.is_empty() {
+            continue;
+        }
+        HeaderValue::from_str(&value).map_err(|_| format!("{} is not a valid header value: {:?}", variable, value))?;
+        headers.push((header, value));
+    }
+    Ok(headers)
+}
+
+fn path_prefix() -> Result<String, String> {
+    let Some(prefix) = env_string("PATH_PREFIX") else {
+        return Ok(String::new());
+    };
+    if !prefix.starts_with('/') {
+        return Err(format!("PATH_PREFIX must start with '/', got {:?}", prefix));
+    }
+    if !prefix.chars().all(|c| c.is_ascii_alphanumeric() || "/-._~".contains(c)) || prefix.contains("//") {
+        return Err(format!("PATH_PREFIX may only contain letters, digits, '-', '.', '_', '~' and single '/', got {:?}", prefix));
+    }
+    Ok(prefix.trim_end_matches('/').to_string())
+}
+
+#[derive(Default)]
+struct PhaseTimes {
+    lock: Cell<Duration>,
+    serialization: Cell<Duration>,
+    rendering: Cell<Duration>,
+}
+
+tokio::task_local! {
+    static PHASE_TIMES: Rc<PhaseTimes>;
+}
+
+#[derive(Clone, Copy)]
+enum Phase {
+    Lock,
+    Serialization,
+    Rendering,
+}
+
+fn timed<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    let Ok(times) = PHASE_TIMES.try_with(Rc::clone) else {
+        return f();
+    };
+    let started = Instant::now();
+    let value = f();
+    let cell = match phase {
+        Phase::Lock => &times.lock,
+        Phase::Serialization => &times.serialization,
+        Phase::Rendering => &times.rendering,
+    };
+    cell.set(cell.get() + started.elapsed());
+    value
+}
+
+trait TimedLock<T> {
+    fn acquire(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> TimedLock<T> for Mutex<T> {
+    fn acquire(&self) -> MutexGuard<'_, T> {
+        timed(Phase::Lock, || self.lock().unwrap())
+    }
+}
+
+fn env_flag(name: &str, default: bool) -> Result<bool, String> {
+    match std::env::var(name) {
+        Ok(value) => match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(format!("{} must be a boolean, got {:?}", name, value)),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
+const FEATURES: &[&str] = &["comment_queue", "comment_dedup", "csrf", "locale_sort"];
+
+#[derive(Clone, Default)]
+struct Features(HashMap<&'static str, bool>);
+
+impl Features {
+    fn parse(header: &str) -> Result<Features, String> {
+        let mut overrides = HashMap::new();
+        for entry in header.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, enabled) = match entry.split_once('=') {
+                Some((name, "on" | "true" | "1")) => (name.trim(), true),
+                Some((name, "off" | "false" | "0")) => (name.trim(), false),
+                Some(_) => return Err(format!("Invalid feature flag {:?}; use name=on or name=off", entry)),
+                None => match entry.strip_prefix('-') {
+                    Some(name) => (name, false),
+                    None => (entry.strip_prefix('+').unwrap_or(entry), true),
+                },
+            };
+            let Some(&name) = FEATURES.iter().find(|&&known| known == name) else {
+                return Err(format!("Unknown feature {:?}; known features are {}", name, FEATURES.join(", ")));
+            };
+            overrides.insert(name, enabled);
+        }
+        Ok(Features(overrides))
+    }
+
+    fn enabled(&self, name: &str, default: bool) -> bool {
+        self.0.get(name).copied().unwrap_or(default)
+    }
+}
+
+impl FromRequest for Features {
+    type Error = actix_web::Error;
+    type Future = std::future::Ready<Result<Features, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let dev_mode = req.app_data::<web::Data<Config>>().is_some_and(|config| config.dev_mode);
+        let header = req.headers().get("X-Feature-Flags").and_then(|value| value.to_str().ok());
+        std::future::ready(match header {
+            Some(header) if dev_mode => Features::parse(header).map_err(actix_web::error::ErrorBadRequest),
+            _ => Ok(Features::default()),
+        })
+    }
+}
+
+struct JsonBody<T>(T);
+
+impl<T> JsonBody<T> {
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+fn json_error(message: String) -> actix_web::Error {
+    let response = HttpResponse::BadRequest().json(serde_json::json!({ "error": message }));
+    InternalError::from_response(message, response).into()
+}
+
+fn body_deadline(timeout: Option<Duration>) -> Option<tokio::time::Instant> {
+    timeout.map(|timeout| tokio::time::Instant::now() + timeout)
+}
+
+fn body_timeout_error() -> actix_web::Error {
+    let message = "Request body was not received in time".to_string();
+    let response = HttpResponse::RequestTimeout().json(serde_json::json!({ "error": message }));
+    InternalError::from_response(message, response).into()
+}
+
+async fn next_chunk<S>(payload: &mut S, deadline: Option<tokio::time::Instant>) -> Result<Option<web::Bytes>, actix_web::Error>
+where
+    S: futures_util::Stream<Item = Result<web::Bytes, actix_web::error::PayloadError>> + Unpin,
+{
+    let chunk = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, payload.next()).await.map_err(|_| body_timeout_error())?,
+        None => payload.next().await,
+    };
+    Ok(chunk.transpose()?)
+}
+
+struct FormBody<T>(T);
+
+impl<T> FormBody<T> {
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for FormBody<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<FormBody<T>, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let deadline = body_deadline(req.app_data::<web::Data<Config>>().and_then(|config| config.body_read_timeout));
+        let form = web::Form::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let form = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, form).await.map_err(|_| body_timeout_error())??,
+                None => form.await?,
+            };
+            Ok(FormBody(form.into_inner()))
+        })
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for JsonBody<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<JsonBody<T>, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let config = req.app_data::<web::Data<Config>>();
+        let lenient = config.is_some_and(|config| config.lenient_json);
+        let deadline = body_deadline(config.and_then(|config| config.body_read_timeout));
+        let is_json = req
+            .mime_type()
+            .ok()
+            .flatten()
+            .is_some_and(|mime| mime.subtype() == "json" || mime.suffix().is_some_and(|suffix| suffix == "json"));
+        let mut payload = payload.take();
+        Box::pin(async move {
+            if !is_json {
+                return Err(json_error("Content-Type must be application/json".to_string()));
+            }
+            let mut body = Vec::new();
+            while let Some(chunk) = next_chunk(&mut payload, deadline).await? {
+                body.extend_from_slice(&chunk);
+                if body.len() > MAX_JSON_BODY_BYTES {
+                    return Err(json_error(format!("JSON body exceeds {} bytes", MAX_JSON_BODY_BYTES)));
+                }
+            }
+            let parsed = if lenient {
+                std::str::from_utf8(&body)
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| json5::from_str(text).map_err(|e| e.to_string()))
+            } else {
+                serde_json::from_slice(&body).map_err(|e| e.to_string())
+            };
+            parsed.map(JsonBody).map_err(|e| json_error(format!("Invalid JSON: {}", e)))
+        })
+    }
+}
+
+#[derive(Serialize, Clone, PartialEq, JsonSchema)]
+struct Post {
+    id: u32,
+    slug: String,
+    title: String,
+    body: String,
+    draft: bool,
+    tags: Vec<String>,
+    one_comment_per_author: bool,
+    content_hash: String,
+    excerpt: String,
+    language: Option<String>,
+    comment_status: CommentStatus,
+    auto_approve_comments: bool,
+    created_at: u64,
+    updated_at: u64,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum CommentStatus {
+    #[default]
+    Auto,
+    Open,
+    Closed,
+}
+
+fn comments_closed(post: &Post, config: &Config) -> bool {
+    match post.comment_status {
+        CommentStatus::Open => false,
+        CommentStatus::Closed => true,
+        CommentStatus::Auto => config
+            .comments_close_after_days
+            .is_some_and(|days| now().saturating_sub(post.created_at) > days * 24 * 60 * 60),
+    }
+}
+
+#[derive(Serialize, Clone, PartialEq, JsonSchema)]
+struct Comment {
+    id: u32,
+    post_id: u32,
+    parent_id: Option<u32>,
+    author: Option<String>,
+    #[serde(skip_serializing)]
+    client_id: Option<String>,
+    text: String,
+    approved: bool,
+    created_at: u64,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct PostData {
+    title: String,
+    body: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    one_comment_per_author: bool,
+    #[serde(default)]
+    language: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FieldError {
+    field: &'static str,
+    message: String,
+}
+
+fn validate_post_data(post: &mut PostData, html: HtmlPolicy) -> Result<(), Vec<FieldError>> {
+    let mut errors = Vec::new();
+    match html.apply("Title", std::mem::take(&mut post.title)) {
+        Ok(title) => post.title = title,
+        Err(message) => return Err(vec![FieldError { field: "title", message }]),
+    }
+    if post.title.trim().is_empty() {
+        errors.push(FieldError { field: "title", message: "Title must not be empty".to_string() });
+    } else if post.title.chars().count() > MAX_TITLE_LENGTH {
+        errors.push(FieldError { field: "title", message: format!("Title must be at most {} characters", MAX_TITLE_LENGTH) });
+    }
+    if post.body.trim().is_empty() {
+        errors.push(FieldError { field: "body", message: "Body must not be empty".to_string() });
+    }
+    if post.tags.len() > MAX_TAGS {
+        errors.push(FieldError { field: "tags", message: format!("At most {} tags are allowed", MAX_TAGS) });
+    } else if post.tags.iter().any(|tag| tag.trim().is_empty()) {
+        errors.push(FieldError { field: "tags", message: "Tags must not be empty".to_string() });
+    } else if post.tags.iter().any(|tag| tag.trim().chars().count() > MAX_TAG_LENGTH) {
+        errors.push(FieldError { field: "tags", message: format!("Tags must be at most {} characters", MAX_TAG_LENGTH) });
+    }
+    if let Some(language) = &mut post.language {
+        *language = language.trim().to_ascii_lowercase();
+        if isolang::Language::from_639_1(language).is_none() {
+            errors.push(FieldError { field: "language", message: "Language must be an ISO 639-1 code such as en".to_string() });
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags.iter().map(|tag| tag.trim().to_lowercase()) {
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct CommentData {
+    post_id: u32,
+    text: String,
+    #[serde(default)]
+    parent_id: Option<u32>,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(skip)]
+    client_id: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    form_token: Option<String>,
+}
+
+// Forwarded addresses are only believed when the connection comes from a trusted proxy, and then
+// the nearest untrusted hop is taken, since clients can put anything at the front of the chain.
+fn client_id(req: &HttpRequest, config: &Config) -> Option<String> {
+    let mut client = req.peer_addr()?.ip();
+    if config.trusted_proxies.contains(&client) {
+        let forwarded: Vec<&str> = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for hop in forwarded.iter().rev() {
+            let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = hop;
+            if !config.trusted_proxies.contains(&hop) {
+                break;
+            }
+        }
+    }
+    Some(client.to_string())
+}
+
+#[derive(Deserialize)]
+struct Pagination {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+impl Pagination {
+    fn apply<T: Clone>(&self, items: &[T], config: &Config) -> Vec<T> {
+        let per_page = self
+            .per_page
+            .unwrap_or(config.default_per_page)
+            .clamp(1, config.max_per_page);
+        let page = self.page.unwrap_or(1).max(1);
+        items.iter().skip((page - 1).saturating_mul(per_page)).take(per_page).cloned().collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct CommentForm {
+    text: String,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    csrf_token: String,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    form_token: Option<String>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn csrf_mac(secret: &[u8], nonce: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac
+}
+
+fn issue_csrf_token(secret: &[u8]) -> String {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let signature = csrf_mac(secret, &nonce).finalize().into_bytes();
+    format!("{}.{}", to_hex(&nonce), to_hex(&signature))
+}
+
+fn form_mac(secret: &[u8], post_id: u32, issued_at: u64) -> Hmac<Sha256> {
+    csrf_mac(secret, format!("comment-form:{}:{}", post_id, issued_at).as_bytes())
+}
+
+fn issue_form_token(secret: &[u8], post_id: u32) -> String {
+    let issued_at = now();
+    format!("{}.{}", issued_at, to_hex(&form_mac(secret, post_id, issued_at).finalize().into_bytes()))
+}
+
+fn form_token_age(secret: &[u8], post_id: u32, token: &str) -> Option<u64> {
+    let (issued_at, signature) = token.split_once('.')?;
+    let issued_at: u64 = issued_at.parse().ok()?;
+    form_mac(secret, post_id, issued_at).verify_slice(&from_hex(signature)?).ok()?;
+    now().checked_sub(issued_at)
+}
+
+// `purpose` keeps confirmation and unsubscribe tokens from being accepted in place of each other.
+fn subscription_mac(secret: &[u8], purpose: &str, email: &str) -> Hmac<Sha256> {
+    csrf_mac(secret, format!("{}:{}", purpose, email).as_bytes())
+}
+
+fn subscription_token(secret: &[u8], purpose: &str, email: &str) -> String {
+    format!("{}.{}", to_hex(email.as_bytes()), to_hex(&subscription_mac(secret, purpose, email).finalize().into_bytes()))
+}
+
+fn subscription_email(secret: &[u8], purpose: &str, token: &str) -> Option<String> {
+    let (email, signature) = token.split_once('.')?;
+    let email = String::from_utf8(from_hex(email)?).ok()?;
+    subscription_mac(secret, purpose, &email).verify_slice(&from_hex(signature)?).ok()?;
+    Some(email)
+}
+
+fn csrf_token_is_valid(secret: &[u8], token: &str) -> bool {
+    let Some((nonce, signature)) = token.split_once('.') else {
+        return false;
+    };
+    match (from_hex(nonce), from_hex(signature)) {
+        (Some(nonce), Some(signature)) => csrf_mac(secret, &nonce).verify_slice(&signature).is_ok(),
+        _ => false,
+    }
+}
+
+fn require_admin(req: &HttpRequest, config: &Config) -> Result<(), HttpResponse> {
+    let Some(admin_token) = &config.admin_token else {
+        return Err(HttpResponse::Forbidden().body("Admin API is disabled; set ADMIN_TOKEN to enable it"));
+    };
+    let provided = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if Sha256::digest(token) == Sha256::digest(admin_token) => Ok(()),
+        _ => Err(HttpResponse::Unauthorized()
+            .insert_header(("WWW-Authenticate", "Bearer"))
+            .finish()),
+    }
+}
+
+fn is_admin(req: &HttpRequest, config: &Config) -> bool {
+    require_admin(req, config).is_ok()
+}
+
+fn prefers_html(req: &HttpRequest) -> bool {
+    let Some(accept) = req.headers().get("Accept").and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    let quality = |wanted: &[&str]| -> f32 {
+        accept
+            .split(',')
+            .filter_map(|entry| {
+                let mut params = entry.split(';').map(str::trim);
+                let media_type = params.next()?;
+                if !wanted.contains(&media_type) {
+                    return None;
+                }
+                let q = params
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                Some(q)
+            })
+            .fold(0.0, f32::max)
+    };
+    quality(&["text/html"]) > quality(&["application/json", "application/*"])
+}
+
+fn unavailable_response(req: &HttpRequest, config: &Config, status: StatusCode, retry_after: u64, message: &str) -> HttpResponse {
+    let (content_type, template, message) = if prefers_html(req) {
+        ("text/html", &config.unavailable_html_template, escape_html(message))
+    } else {
+        let quoted = serde_json::to_string(message).unwrap_or_default();
+        ("application/json", &config.unavailable_json_template, quoted[1..quoted.len() - 1].to_string())
+    };
+    let body = template
+        .replace("{status}", status.as_str())
+        .replace("{retry_after}", &retry_after.to_string())
+        .replace("{message}", &message);
+    HttpResponse::build(status)
+        .content_type(content_type)
+        .insert_header(("Retry-After", retry_after.to_string()))
+        .body(body)
+}
+
+fn find_links(text: &str) -> Vec<std::ops::Range<usize>> {
+    let mut links = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = ["http://", "https://"].iter().filter_map(|scheme| text[offset..].find(scheme)).min().map(|start| offset + start) {
+        let len = text[start..].find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"')).unwrap_or(text.len() - start);
+        let mut url = &text[start..start + len];
+        loop {
+            let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?', '\'']);
+            let trimmed = match trimmed.strip_suffix(')') {
+                Some(inner) if inner.matches('(').count() < inner.matches(')').count() + 1 => inner,
+                _ => trimmed,
+            };
+            if trimmed.len() == url.len() {
+                break;
+            }
+            url = trimmed;
+        }
+        if url.len() > url.find("://").unwrap() + 3 {
+            links.push(start..start + url.len());
+        }
+        offset = start + len.max(1);
+    }
+    links
+}
+
+fn linkify_comment(text: &str) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut last = 0;
+    for link in find_links(text) {
+        let url = escape_html(&text[link.clone()]);
+        html.push_str(&escape_html(&text[last..link.start]));
+        html.push_str(&format!(r#"<a href="{url}" rel="nofollow noopener" target="_blank" class="underline">{url}</a>"#));
+        last = link.end;
+    }
+    html.push_str(&escape_html(&text[last..]));
+    html
+}
+
+fn validate_comment_text(text: &str) -> Result<(), String> {
+    if text.trim().is_empty() {
+        Err("Comment text must not be empty".to_string())
+    } else if text.chars().count() > MAX_COMMENT_LENGTH {
+        Err(format!("Comment text must be at most {} characters", MAX_COMMENT_LENGTH))
+    } else {
+        Ok(())
+    }
+}
+
+fn normalize_author(author: Option<String>) -> Result<Option<String>, String> {
+    let Some(author) = author.map(|author| author.trim().to_string()).filter(|author| !author.is_empty()) else {
+        return Ok(None);
+    };
+    if author.chars().count() > MAX_AUTHOR_LENGTH {
+        return Err(format!("Author must be at most {} characters", MAX_AUTHOR_LENGTH));
+    }
+    Ok(Some(author))
+}
+
+fn add_comment(comments_db: &mut HashMap<u32, Vec<Comment>>, last_ids: &mut HashMap<u32, u32>, comment: CommentData, approved: bool) -> Comment {
+    let comments = comments_db.entry(comment.post_id).or_default();
+    let last_id = last_ids.entry(comment.post_id).or_default();
+    *last_id = (*last_id).max(comments.last().map_or(0, |last| last.id)) + 1;
+    let new_comment = Comment {
+        id: *last_id,
+        post_id: comment.post_id,
+        parent_id: comment.parent_id,
+        author: comment.author,
+        client_id: comment.client_id,
+        text: comment.text,
+        approved,
+        created_at: now(),
+    };
+    comments.push(new_comment.clone());
+    new_comment
+}
+
+#[derive(Default)]
+struct RecentHashes {
+    entries: VecDeque<(u64, [u8; 32])>,
+    counts: HashMap<[u8; 32], usize>,
+}
+
+impl RecentHashes {
+    fn seen(&mut self, hash: &[u8; 32], now: u64, window_secs: u64, capacity: usize) -> bool {
+        while let Some(&(seen_at, old)) = self.entries.front() {
+            if seen_at + window_secs >= now && self.entries.len() < capacity.max(1) {
+                break;
+            }
+            self.entries.pop_front();
+            if let Some(count) = self.counts.get_mut(&old) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&old);
+                }
+            }
+        }
+        self.counts.contains_key(hash)
+    }
+
+    fn record(&mut self, hash: [u8; 32], now: u64) {
+        self.entries.push_back((now, hash));
+        *self.counts.entry(hash).or_default() += 1;
+    }
+}
+
+#[derive(Default)]
+struct RecentNonces {
+    entries: VecDeque<(u64, (u32, String), u32)>,
+    comments: HashMap<(u32, String), u32>,
+}
+
+impl RecentNonces {
+    fn expire(&mut self, now: u64, window_secs: u64) {
+        while let Some((seen_at, key, comment_id)) = self.entries.front() {
+            if seen_at + window_secs >= now && self.entries.len() < MAX_REMEMBERED_NONCES {
+                break;
+            }
+            if self.comments.get(key) == Some(comment_id) {
+                self.comments.remove(key);
+            }
+            self.entries.pop_front();
+        }
+    }
+
+    fn record(&mut self, key: (u32, String), comment_id: u32, now: u64) {
+        self.entries.push_back((now, key.clone(), comment_id));
+        self.comments.insert(key, comment_id);
+    }
+}
+
+fn comment_hash(text: &str) -> [u8; 32] {
+    let normalized = text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    Sha256::digest(normalized).into()
+}
+
+enum CommentError {
+    Invalid(String),
+    Duplicate,
+    AlreadyCommented,
+    Closed,
+    PostNotFound,
+    PostDeleted,
+}
+
+impl CommentError {
+    fn status(&self) -> StatusCode {
+        match self {
+            CommentError::Invalid(_) => StatusCode::BAD_REQUEST,
+            CommentError::Duplicate | CommentError::AlreadyCommented => StatusCode::CONFLICT,
+            CommentError::Closed => StatusCode::FORBIDDEN,
+            CommentError::PostNotFound => StatusCode::NOT_FOUND,
+            CommentError::PostDeleted => StatusCode::GONE,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            CommentError::Invalid(reason) => reason.clone(),
+            CommentError::Duplicate => "The same comment was already posted recently".to_string(),
+            CommentError::AlreadyCommented => "This post accepts only one comment per participant".to_string(),
+            CommentError::Closed => "Comments are closed on this post".to_string(),
+            CommentError::PostNotFound => "Post not found".to_string(),
+            CommentError::PostDeleted => "This post has been deleted and no longer accepts comments".to_string(),
+        }
+    }
+
+    fn response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).json(serde_json::json!({ "error": self.message() }))
+    }
+}
+
+enum Submission {
+    Created(Comment),
+    Replayed(Comment),
+}
+
+impl Submission {
+    fn comment(self) -> Comment {
+        match self {
+            Submission::Created(comment) | Submission::Replayed(comment) => comment,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CommentService {
+    config: Config,
+    posts_db: PostsDb,
+    comments_db: CommentsDb,
+    recent_hashes: RecentHashesDb,
+    recent_nonces: RecentNoncesDb,
+    deleted_posts: DeletedPostsDb,
+    last_comment_ids: CommentIdsDb,
+}
+
+impl CommentService {
+    fn submit(&self, mut comment: CommentData, features: &Features) -> Result<Submission, CommentError> {
+        let Some(nonce) = comment.nonce.take().filter(|nonce| !nonce.is_empty() && self.config.comment_nonce_window_secs > 0) else {
+            return self.create(comment, features).map(Submission::Created);
+        };
+        if nonce.chars().count() > MAX_NONCE_LENGTH {
+            return Err(CommentError::Invalid(format!("Nonce must be at most {} characters", MAX_NONCE_LENGTH)));
+        }
+        let now = now();
+        let mut nonces = self.recent_nonces.acquire();
+        nonces.expire(now, self.config.comment_nonce_window_secs);
+        let key = (comment.post_id, nonce);
+        if let Some(&comment_id) = nonces.comments.get(&key) {
+            let existing = self
+                .comments_db
+                .acquire()
+                .get(&key.0)
+                .and_then(|comments| comments.iter().find(|existing| existing.id == comment_id).cloned());
+            if let Some(existing) = existing {
+                return Ok(Submission::Replayed(existing));
+            }
+        }
+        let created = self.create(comment, features)?;
+        nonces.record(key, created.id, now);
+        Ok(Submission::Created(created))
+    }
+
+    fn create(&self, mut comment: CommentData, features: &Features) -> Result<Comment, CommentError> {
+        if let Some(min_secs) = self.config.comment_min_seconds {
+            let age = comment
+                .form_token
+                .as_deref()
+                .and_then(|token| form_token_age(&self.config.csrf_secret, comment.post_id, token))
+                .filter(|&age| age <= FORM_TOKEN_MAX_AGE_SECS)
+                .ok_or_else(|| CommentError::Invalid("Missing or expired form token; reload the page and try again".to_string()))?;
+            if age < min_secs {
+                return Err(CommentError::Invalid("Comment submitted too quickly; please wait a moment and try again".to_string()));
+            }
+        }
+        comment.text = self.config.html_in_text.apply("Comment", comment.text).map_err(CommentError::Invalid)?;
+        validate_comment_text(&comment.text).map_err(CommentError::Invalid)?;
+        comment.author = comment
+            .author
+            .map(|author| self.config.html_in_text.apply("Author", author))
+            .transpose()
+            .and_then(normalize_author)
+            .map_err(CommentError::Invalid)?;
+        let mut approved = true;
+        let dedup = match (features.enabled("comment_dedup", self.config.comment_dedup != DedupMode::Off), self.config.comment_dedup) {
+            (false, _) => DedupMode::Off,
+            (true, DedupMode::Off) => DedupMode::Reject,
+            (true, mode) => mode,
+        };
+        let hash = comment_hash(&comment.text);
+        let seen = |hashes: &mut RecentHashes| {
+            dedup != DedupMode::Off && hashes.seen(&hash, now(), self.config.comment_dedup_window_secs, self.config.comment_dedup_capacity)
+        };
+        if seen(&mut self.recent_hashes.acquire()) {
+            if dedup == DedupMode::Reject {
+                return Err(CommentError::Duplicate);
+            }
+            approved = false;
+        }
+        if let Some(max_links) = self.config.comment_max_links.filter(|&max| find_links(&comment.text).len() > max) {
+            if !self.config.flag_excess_links {
+                return Err(CommentError::Invalid(format!("Comments may contain at most {} links", max_links)));
+            }
+            approved = false;
+        }
+        let post = self
+            .posts_db
+            .acquire()
+            .get(&comment.post_id)
+            .map(|post| (comments_closed(post, &self.config), post.one_comment_per_author, post.auto_approve_comments));
+        let Some((closed, one_per_author, auto_approve)) = post else {
+            return Err(match self.deleted_posts.acquire().contains_key(&comment.post_id) {
+                true => CommentError::PostDeleted,
+                false => CommentError::PostNotFound,
+            });
+        };
+        if closed {
+            return Err(CommentError::Closed);
+        }
+        if self.config.moderate_comments && !auto_approve {
+            approved = false;
+        }
+        let mut comments_db = self.comments_db.acquire();
+        if one_per_author {
+            let same_participant = |existing: &Comment| {
+                let same_author = match (&existing.author, &comment.author) {
+                    (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+                    _ => false,
+                };
+                let same_client = existing.client_id.is_some() && existing.client_id == comment.client_id;
+                same_author || same_client
+            };
+            if comments_db.get(&comment.post_id).is_some_and(|comments| comments.iter().any(same_participant)) {
+                return Err(CommentError::AlreadyCommented);
+            }
+        }
+        if let Some(parent_id) = comment.parent_id {
+            let parent_exists = comments_db
+                .get(&comment.post_id)
+                .is_some_and(|comments| comments.iter().any(|existing| existing.id == parent_id));
+            if !parent_exists {
+                return Err(CommentError::Invalid(format!("Parent comment {} does not exist on this post", parent_id)));
+            }
+        }
+        let mut hashes = self.recent_hashes.acquire();
+        if seen(&mut hashes) {
+            if dedup == DedupMode::Reject {
+                return Err(CommentError::Duplicate);
+            }
+            approved = false;
+        }
+        let created = add_comment(&mut comments_db, &mut self.last_comment_ids.acquire(), comment, approved);
+        if dedup != DedupMode::Off {
+            hashes.record(hash, created.created_at);
+        }
+        Ok(created)
+    }
+}
+
+fn heading_anchor(text: &str, used: &mut HashMap<String, usize>) -> String {
+    let mut anchor: String = text
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            c if c.is_whitespace() => Some('-'),
+            _ => None,
+        })
+        .collect();
+    if anchor.is_empty() {
+        anchor = "section".to_string();
+    }
+    let count = used.entry(anchor.clone()).or_insert(0);
+    let unique = if *count == 0 { anchor } else { format!("{}-{}", anchor, count) };
+    *count += 1;
+    unique
+}
+
+fn heading_text(events: &[Event]) -> String {
+    events
+        .iter()
+        .take_while(|event| !matches!(event, Event::End(TagEnd::Heading(_))))
+        .filter_map(|event| match event {
+            Event::Text(text) | Event::Code(text) => Some(text.as_ref()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    let scheme = url.split(':').next().unwrap_or_default().trim().to_ascii_lowercase();
+    if url.contains(':') && !matches!(scheme.as_str(), "http" | "https" | "mailto") && !url.starts_with('/') && !url.starts_with('#') {
+        CowStr::from("#")
+    } else {
+        url
+    }
+}
+
+fn markdown_events(body: &str) -> Vec<Event<'_>> {
+    timed(Phase::Rendering, || parse_markdown(body))
+}
+
+fn parse_markdown(body: &str) -> Vec<Event<'_>> {
+    let mut events: Vec<Event> = Parser::new(body)
+        .map(|event| match event {
+            Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+            Event::Start(Tag::Link { link_type, dest_url, title, id }) => Event::Start(Tag::Link {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            }),
+            Event::Start(Tag::Image { link_type, dest_url, title, id }) => Event::Start(Tag::Image {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            }),
+            event => event,
+        })
+        .collect();
+    let mut used = HashMap::new();
+    for start in 0..events.len() {
+        if !matches!(events[start], Event::Start(Tag::Heading { .. })) {
+            continue;
+        }
+        let text = heading_text(&events[start + 1..]);
+        if let Event::Start(Tag::Heading { id, .. }) = &mut events[start] {
+            *id = Some(CowStr::from(heading_anchor(&text, &mut used)));
+        }
+    }
+    events
+}
+
+fn plain_text<'a>(events: impl IntoIterator<Item = &'a Event<'a>>) -> String {
+    let mut text = String::new();
+    for event in events {
+        match event {
+            Event::Text(part) | Event::Code(part) => text.push_str(part),
+            Event::SoftBreak | Event::HardBreak => text.push(' '),
+            Event::End(TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item | TagEnd::CodeBlock | TagEnd::BlockQuote(_)) => text.push(' '),
+            _ => {}
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn truncate_words(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(limit).collect();
+    let cut = match cut.rfind(' ') {
+        Some(space) if space > 0 => &cut[..space],
+        _ => &cut,
+    };
+    format!("{}…", cut.trim_end_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation()))
+}
+
+fn post_excerpt(body: &str, config: &Config) -> String {
+    let events = markdown_events(body);
+    let has_paragraph_break = body.trim().lines().any(|line| line.trim().is_empty());
+    if config.excerpt_mode == ExcerptMode::Paragraph && has_paragraph_break {
+        if let Some(start) = events.iter().position(|event| matches!(event, Event::Start(Tag::Paragraph))) {
+            let paragraph = plain_text(events[start + 1..].iter().take_while(|event| !matches!(event, Event::End(TagEnd::Paragraph))));
+            if !paragraph.is_empty() {
+                return paragraph;
+            }
+        }
+    }
+    truncate_words(&plain_text(&events), config.excerpt_length)
+}
+
+fn render_markdown(body: &str) -> String {
+    let events = markdown_events(body);
+    timed(Phase::Rendering, || {
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, events.into_iter());
+        html
+    })
+}
+
+#[derive(Serialize)]
+struct OutlineEntry {
+    level: u8,
+    text: String,
+    anchor: String,
+    children: Vec<OutlineEntry>,
+}
+
+fn post_outline(body: &str) -> Vec<OutlineEntry> {
+    let events = markdown_events(body);
+    let mut flat = Vec::new();
+    for (start, event) in events.iter().enumerate() {
+        let Event::Start(Tag::Heading { level, id, .. }) = event else {
+            continue;
+        };
+        let text = heading_text(&events[start + 1..]);
+        let level = match level {
+            HeadingLevel::H1 => 1,
+            HeadingLevel::H2 => 2,
+            HeadingLevel::H3 => 3,
+            HeadingLevel::H4 => 4,
+            HeadingLevel::H5 => 5,
+            HeadingLevel::H6 => 6,
+        };
+        let anchor = id.as_deref().unwrap_or_default().to_string();
+        flat.push(OutlineEntry { level, text, anchor, children: Vec::new() });
+    }
+
+    let mut roots: Vec<OutlineEntry> = Vec::new();
+    let mut stack: Vec<OutlineEntry> = Vec::new();
+    for entry in flat {
+        while stack.last().is_some_and(|open| open.level >= entry.level) {
+            let done = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(done),
+                None => roots.push(done),
+            }
+        }
+        stack.push(entry);
+    }
+    while let Some(done) = stack.pop() {
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(done),
+            None => roots.push(done),
+        }
+    }
+    roots
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(str::to_lowercase)
+}
+
+#[derive(Default)]
+struct SimilarityIndex {
+    term_frequencies: HashMap<u32, HashMap<String, f64>>,
+    document_frequencies: HashMap<String, usize>,
+}
+
+impl SimilarityIndex {
+    fn update(&mut self, post: &Post) {
+        self.remove(post.id);
+        let mut counts: HashMap<String, f64> = HashMap::new();
+        for term in tokenize(&post.body) {
+            *counts.entry(term).or_default() += 1.0;
+        }
+        let total: f64 = counts.values().sum();
+        for (term, count) in counts.iter_mut() {
+            *self.document_frequencies.entry(term.clone()).or_default() += 1;
+            *count /= total;
+        }
+        self.term_frequencies.insert(post.id, counts);
+    }
+
+    fn remove(&mut self, post_id: u32) {
+        let Some(old) = self.term_frequencies.remove(&post_id) else {
+            return;
+        };
+        for term in old.keys() {
+            if let Some(df) = self.document_frequencies.get_mut(term) {
+                *df -= 1;
+                if *df == 0 {
+                    self.document_frequencies.remove(term);
+                }
+            }
+        }
+    }
+
+    fn vector(&self, post_id: u32) -> HashMap<&str, f64> {
+        let documents = self.term_frequencies.len() as f64;
+        self.term_frequencies
+            .get(&post_id)
+            .into_iter()
+            .flatten()
+            .map(|(term, tf)| {
+                let df = self.document_frequencies.get(term).copied().unwrap_or(0) as f64;
+                (term.as_str(), tf * (((1.0 + documents) / (1.0 + df)).ln() + 1.0))
+            })
+            .collect()
+    }
+
+    fn similar(&self, post_id: u32, limit: usize) -> Vec<(u32, f64)> {
+        let target = self.vector(post_id);
+        let target_norm = target.values().map(|w| w * w).sum::<f64>().sqrt();
+        if target_norm == 0.0 {
+            return Vec::new();
+        }
+        let mut scores: Vec<(u32, f64)> = self
+            .term_frequencies
+            .keys()
+            .filter(|&&id| id != post_id)
+            .filter_map(|&id| {
+                let other = self.vector(id);
+                let norm = other.values().map(|w| w * w).sum::<f64>().sqrt();
+                let dot: f64 = other.iter().filter_map(|(term, w)| target.get(term).map(|t| t * w)).sum();
+                (dot > 0.0).then(|| (id, dot / (norm * target_norm)))
+            })
+            .collect();
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores.truncate(limit);
+        scores
+    }
+}
+
+fn search_terms(post: &Post) -> HashSet<String> {
+    tokenize(&post.title).chain(tokenize(&post.body)).collect()
+}
+
+#[derive(Default)]
+struct SearchIndex {
+    postings: HashMap<String, HashSet<u32>>,
+    documents: HashMap<u32, HashSet<String>>,
+    building: bool,
+    dirty: HashSet<u32>,
+}
+
+impl SearchIndex {
+    fn update(&mut self, post: &Post) {
+        self.remove(post.id);
+        let terms = search_terms(post);
+        for term in &terms {
+            self.postings.entry(term.clone()).or_default().insert(post.id);
+        }
+        self.documents.insert(post.id, terms);
+    }
+
+    fn remove(&mut self, post_id: u32) {
+        if self.building {
+            self.dirty.insert(post_id);
+        }
+        let Some(terms) = self.documents.remove(&post_id) else {
+            return;
+        };
+        for term in terms {
+            if let Some(ids) = self.postings.get_mut(&term) {
+                ids.remove(&post_id);
+                if ids.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    fn search(&self, terms: &[String]) -> HashSet<u32> {
+        let mut sets = terms.iter().map(|term| self.postings.get(term));
+        let Some(Some(first)) = sets.next() else {
+            return HashSet::new();
+        };
+        let mut ids = first.clone();
+        for set in sets {
+            ids.retain(|id| set.is_some_and(|set| set.contains(id)));
+        }
+        ids
+    }
+}
+
+fn rebuild_search_index(posts_db: &PostsDb, search_db: &SearchDb) {
+    let started = Instant::now();
+    let snapshot: Vec<Post> = posts_db.acquire().values().cloned().collect();
+    let mut rebuilt = SearchIndex::default();
+    for post in &snapshot {
+        rebuilt.update(post);
+    }
+    let posts = posts_db.acquire();
+    let mut search = search_db.acquire();
+    for id in std::mem::take(&mut search.dirty) {
+        match posts.get(&id) {
+            Some(post) => rebuilt.update(post),
+            None => rebuilt.remove(id),
+        }
+    }
+    *search = rebuilt;
+    log::info!("Search index rebuilt with {} posts in {:?}", search.documents.len(), started.elapsed());
+}
+
+fn start_search_rebuild(posts_db: &PostsDb, search_db: &SearchDb) -> bool {
+    let mut search = search_db.acquire();
+    if search.building {
+        return false;
+    }
+    search.building = true;
+    drop(search);
+    let (posts_db, search_db) = (posts_db.clone(), search_db.clone());
+    tokio::task::spawn_blocking(move || rebuild_search_index(&posts_db, &search_db));
+    true
+}
+
+async fn index(config: web::Data<Config>) -> impl Responder {
+    let html = r#"
+    <!DOCTYPE html>
+    <html>
+    <head>
+        <title>Blog Application</title>
+        <script src="https://cdn.tailwindcss.com"></script>
+    </head>
+    <body class="text-gray-900">
+        <div class="container mx-auto p-4">
+            <h1 class="text-4xl font-bold mb-4">Blog Posts</h1>
+            <div id="posts-list" class="space-y-4">Loading...</div>
+            <p id="empty-state" class="hidden text-lg text-gray-600">{empty_state}</p>
+            <h2 class="text-2xl font-semibold mt-8 mb-2">Create New Post</h2>
+            <div class="space-y-2">
+                <input id="title" type="text" placeholder="Title" class="p-2 border border-gray-300 rounded w-full"/>
+                <textarea id="body" placeholder="Body" class="p-2 border border-gray-300 rounded w-full h-40"></textarea>
+                <button onclick="createPost()" class="px-4 py-2 bg-black text-white rounded">Create Post</button>
+            </div>
+        </div>
+        <script>
+            let formToken = null;
+
+            async function fetchPosts() {
+                let response = await fetch('{prefix}/api/posts?envelope=false');
+                let posts = await response.json();
+                let postsList = document.getElementById('posts-list');
+                document.getElementById('empty-state').classList.toggle('hidden', posts.length > 0);
+                postsList.innerHTML = posts.map(post => `
+                    <div class="p-4 bg-white border border-black rounded cursor-pointer" onclick="viewPost(${post.id})">
+                        <h2 class="text-2xl font-bold">${post.title}</h2>
+                        <p class="mt-2">${post.excerpt}</p>
+                    </div>
+                `).join('');
+            }
+
+            async function viewPost(postId) {
+                let response = await fetch(`{prefix}/api/posts/${postId}?envelope=false`);
+                let post = await response.json();
+                let commentsResponse = await fetch(`{prefix}/api/posts/${postId}/comments?envelope=false`);
+                let comments = await commentsResponse.json();
+                let tokenResponse = await fetch(`{prefix}/api/posts/${postId}/form-token?envelope=false`);
+                formToken = (await tokenResponse.json()).token;
+                document.body.innerHTML = `
+                    <div class="container mx-auto p-4">
+                        <h1 class="text-4xl font-bold mb-4">${post.title}</h1>
+                        <p class="text-lg mb-4">${post.body}</p>
+                        <h2 class="text-2xl font-semibold mb-2">Comments</h2>
+                        <div id="comments-list" class="space-y-4">${comments.map(comment => `
+                            <div class="p-4 bg-white border border-black rounded">
+                                <p>${comment.text}</p>
+                            </div>
+                        `).join('')}</div>
+                        <h2 class="text-2xl font-semibold mt-8 mb-2">Add Comment</h2>
+                        <textarea id="comment-text" placeholder="Your comment" class="p-2 border border-gray-300 rounded w-full h-40"></textarea>
+                        <button onclick="addComment(${postId})" class="px-4 py-2 bg-black text-white rounded">Add Comment</button>
+                    </div>
+                `;
+            }
+
+            async function createPost() {
+                let title = document.getElementById('title').value;
+                let body = document.getElementById('body').value;
+                let response = await fetch('{prefix}/api/posts', {
+                    method: 'POST',
+                    headers: {
+                        'Content-Type': 'application/json'
+                    },
+                    body: JSON.stringify({ title, body })
+                });
+                if (response.ok) {
+                    fetchPosts();
+                }
+            }
+
+            async function addComment(postId) {
+                let text = document.getElementById('comment-text').value;
+                let response = await fetch('{prefix}/api/comments', {
+                    method: 'POST',
+                    headers: {
+                        'Content-Type': 'application/json'
+                    },
+                    body: JSON.stringify({ post_id: postId, text, form_token: formToken })
+                });
+                if (response.ok) {
+                    viewPost(postId);
+                }
+            }
+
+            window.onload = fetchPosts;
+        </script>
+    </body>
+    </html>
+    "#
+    .replace("{prefix}", &config.path_prefix)
+    .replace("{empty_state}", &escape_html(&config.empty_index_message));
+    HttpResponse::Ok().content_type("text/html").body(html)
+}
+
+#[derive(Deserialize)]
+struct PostListQuery {
+    sort: Option<String>,
+    order: Option<String>,
+    tags: Option<String>,
+    #[serde(rename = "match")]
+    tag_match: Option<String>,
+    lang: Option<String>,
+}
+
+fn tag_filter(list: &PostListQuery) -> Result<Option<(Vec<String>, bool)>, String> {
+    let match_all = match list.tag_match.as_deref() {
+        None | Some("any") => false,
+        Some("all") => true,
+        Some(other) => return Err(format!("match must be all or any, got {:?}", other)),
+    };
+    let tags = normalize_tags(
+        list.tags
+            .iter()
+            .flat_map(|tags| tags.split(','))
+            .filter(|tag| !tag.trim().is_empty())
+            .map(str::to_string)
+            .collect(),
+    );
+    Ok((!tags.is_empty()).then_some((tags, match_all)))
+}
+
+fn compare_titles(a: &str, b: &str, collator: Option<&CollatorBorrowed<'static>>) -> CmpOrdering {
+    match collator {
+        Some(collator) => collator.compare(a, b),
+        None => a.to_lowercase().cmp(&b.to_lowercase()),
+    }
+}
+
+fn sort_posts(posts: &mut [Post], query: &PostListQuery, config: &Config, features: &Features) -> Result<(), String> {
+    let collator = config.title_collator.as_deref().filter(|_| features.enabled("locale_sort", true));
+    match query.sort.as_deref().unwrap_or("id") {
+        "id" => posts.sort_by_key(|post| post.id),
+        "title" => posts.sort_by(|a, b| compare_titles(&a.title, &b.title, collator).then(a.id.cmp(&b.id))),
+        "created_at" => posts.sort_by_key(|post| (post.created_at, post.id)),
+        "updated_at" => posts.sort_by_key(|post| (post.updated_at, post.id)),
+        other => return Err(format!("Unknown sort field {:?}", other)),
+    }
+    match query.order.as_deref().unwrap_or("asc") {
+        "asc" => {}
+        "desc" => posts.reverse(),
+        other => return Err(format!("Unknown sort order {:?}", other)),
+    }
+    Ok(())
+}
+
+async fn get_posts(req: HttpRequest, pagination: web::Query<Pagination>, list: web::Query<PostListQuery>, features: Features, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let include_drafts = is_admin(&req, &config);
+    let db = db.acquire();
+    let tags = match tag_filter(&list) {
+        Ok(tags) => tags,
+        Err(message) => return HttpResponse::BadRequest().json(serde_json::json!({ "error": message })),
+    };
+    let has_tags = |post: &Post| match &tags {
+        None => true,
+        Some((tags, true)) => tags.iter().all(|tag| post.tags.contains(tag)),
+        Some((tags, false)) => tags.iter().any(|tag| post.tags.contains(tag)),
+    };
+    let mut posts: Vec<Post> = db
+        .values()
+        .filter(|post| include_drafts || !post.draft)
+        .filter(|post| has_tags(post))
+        .filter(|post| list.lang.as_ref().is_none_or(|lang| post.language.as_ref().is_some_and(|language| language.eq_ignore_ascii_case(lang.trim()))))
+        .cloned()
+        .collect();
+    if let Err(message) = sort_posts(&mut posts, &list, &config, &features) {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": message }));
+    }
+    let page = pagination.apply(&posts, &config);
+    let last_modified = page.iter().map(|post| post.updated_at).max();
+    json_with_validators(HttpResponse::Ok().insert_header(("X-Total-Count", posts.len())), &page, last_modified)
+}
+
+fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "post".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+fn unique_slug(base: &str, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(base) {
+        return base.to_string();
+    }
+    (2..)
+        .map(|n| format!("{}-{}", base, n))
+        .find(|candidate| !taken(candidate))
+        .expect("an unused slug suffix exists")
+}
+
+#[derive(Deserialize)]
+struct LimitQuery {
+    limit: Option<usize>,
+}
+
+fn newest_first(a: &Post, b: &Post) -> CmpOrdering {
+    b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id))
+}
+
+async fn get_latest_posts(req: HttpRequest, query: web::Query<LimitQuery>, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let include_drafts = is_admin(&req, &config);
+    let limit = query.limit.unwrap_or(DEFAULT_LATEST_LIMIT).clamp(1, MAX_LATEST_LIMIT);
+    let db = db.acquire();
+    let mut posts: Vec<&Post> = db.values().filter(|post| include_drafts || !post.draft).collect();
+    if posts.len() > limit {
+        posts.select_nth_unstable_by(limit - 1, |a, b| newest_first(a, b));
+        posts.truncate(limit);
+    }
+    posts.sort_by(|a, b| newest_first(a, b));
+    HttpResponse::Ok().json(posts)
+}
+
+#[derive(Deserialize)]
+struct RecentlyEditedQuery {
+    limit: Option<usize>,
+    #[serde(default)]
+    edited_only: bool,
+}
+
+async fn get_recently_edited_posts(req: HttpRequest, query: web::Query<RecentlyEditedQuery>, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let include_drafts = is_admin(&req, &config);
+    let limit = query.limit.unwrap_or(DEFAULT_LATEST_LIMIT).clamp(1, MAX_LATEST_LIMIT);
+    let db = db.acquire();
+    let mut posts: Vec<&Post> = db
+        .values()
+        .filter(|post| include_drafts || !post.draft)
+        .filter(|post| !query.edited_only || post.updated_at != post.created_at)
+        .collect();
+    posts.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then(b.id.cmp(&a.id)));
+    posts.truncate(limit);
+    HttpResponse::Ok().json(posts)
+}
+
+#[derive(Deserialize)]
+struct ActivePostsQuery {
+    limit: Option<usize>,
+    #[serde(default)]
+    commented_only: bool,
+}
+
+#[derive(Serialize)]
+struct ActivePost<'a> {
+    #[serde(flatten)]
+    post: &'a Post,
+    last_comment_at: Option<u64>,
+}
+
+async fn get_active_posts(req: HttpRequest, query: web::Query<ActivePostsQuery>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    let include_drafts = is_admin(&req, &config);
+    let limit = query.limit.unwrap_or(DEFAULT_LATEST_LIMIT).clamp(1, MAX_LATEST_LIMIT);
+    let db = db.acquire();
+    let comments_db = comments_db.acquire();
+    let mut posts: Vec<ActivePost> = db
+        .values()
+        .filter(|post| include_drafts || !post.draft)
+        .map(|post| ActivePost {
+            post,
+            last_comment_at: comments_db
+                .get(&post.id)
+                .into_iter()
+                .flatten()
+                .filter(|comment| comment.approved)
+                .map(|comment| comment.created_at)
+                .max(),
+        })
+        .filter(|active| !query.commented_only || active.last_comment_at.is_some())
+        .collect();
+    posts.sort_by(|a, b| b.last_comment_at.cmp(&a.last_comment_at).then_with(|| newest_first(a.post, b.post)));
+    posts.truncate(limit);
+    HttpResponse::Ok().json(posts)
+}
+
+#[derive(Deserialize)]
+struct TagGroupsQuery {
+    tags: Option<usize>,
+    per_tag: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct TaggedPost {
+    id: u32,
+    title: String,
+}
+
+async fn get_posts_by_tag(query: web::Query<TagGroupsQuery>, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let tag_limit = query.tags.unwrap_or(DEFAULT_TAG_GROUPS).clamp(1, config.max_per_page);
+    let per_tag = query.per_tag.unwrap_or(DEFAULT_POSTS_PER_TAG).clamp(1, config.max_per_page);
+    let db = db.acquire();
+    let mut groups: HashMap<&str, Vec<&Post>> = HashMap::new();
+    for post in db.values().filter(|post| !post.draft) {
+        for tag in &post.tags {
+            groups.entry(tag.as_str()).or_default().push(post);
+        }
+    }
+    let mut ranked: Vec<(&str, Vec<&Post>)> = groups.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(b.0)));
+    let grouped: BTreeMap<&str, Vec<TaggedPost>> = ranked
+        .into_iter()
+        .take(tag_limit)
+        .map(|(tag, mut posts)| {
+            posts.sort_by(|a, b| newest_first(a, b));
+            let posts = posts
+                .into_iter()
+                .take(per_tag)
+                .map(|post| TaggedPost { id: post.id, title: post.title.clone() })
+                .collect();
+            (tag, posts)
+        })
+        .collect();
+    HttpResponse::Ok().json(grouped)
+}
+
+fn content_hash(title: &str, body: &str) -> String {
+    let body = body.lines().map(str::trim_end).collect::<Vec<_>>().join("\n");
+    let mut hasher = Sha256::new();
+    hasher.update(title.trim());
+    hasher.update(b"\n\n");
+    hasher.update(body.trim());
+    to_hex(&hasher.finalize())
+}
+
+fn detect_language(title: &str, body: &str) -> Option<String> {
+    let info = whatlang::detect(&format!("{}\n\n{}", title, body)).filter(|info| info.is_reliable())?;
+    isolang::Language::from_639_3(info.lang().code())?.to_639_1().map(str::to_string)
+}
+
+fn insert_post(db: &mut HashMap<u32, Post>, deleted: &DeletedPostsDb, last_id: &LastPostIdDb, similarity: &SimilarityDb, search: &SearchDb, post: PostData, config: &Config) -> Post {
+    // The high-water mark keeps ids of purged posts from being handed out again.
+    let mut last_id = last_id.acquire();
+    let id = db.keys().chain(deleted.acquire().keys()).chain(std::iter::once(&*last_id)).max().map_or(1, |id| id + 1);
+    *last_id = id;
+    let created_at = now();
+    let slug = unique_slug(&slugify(&post.title), |slug| db.values().any(|post| post.slug == slug));
+    let hash = content_hash(&post.title, &post.body);
+    let excerpt = post_excerpt(&post.body, config);
+    let language = post.language.or_else(|| detect_language(&post.title, &post.body));
+    let new_post = Post {
+        id,
+        slug,
+        title: post.title,
+        body: post.body,
+        draft: post.draft,
+        tags: normalize_tags(post.tags),
+        one_comment_per_author: post.one_comment_per_author,
+        content_hash: hash,
+        excerpt,
+        language,
+        comment_status: CommentStatus::Auto,
+        auto_approve_comments: false,
+        created_at,
+        updated_at: created_at,
+    };
+    similarity.acquire().update(&new_post);
+    search.acquire().update(&new_post);
+    db.insert(id, new_post.clone());
+    new_post
+}
+
+fn suggest_tags(body: &str, stopwords: &HashSet<String>, limit: usize) -> Vec<String> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for word in tokenize(body) {
+        if word.chars().count() >= 3 && word.chars().count() <= MAX_TAG_LENGTH && !word.chars().all(|c| c.is_numeric()) && !stopwords.contains(&word) {
+            *counts.entry(word).or_default() += 1;
+        }
+    }
+    let mut ranked: Vec<(String, usize)> = counts.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.into_iter().take(limit).map(|(word, _)| word).collect()
+}
+
+#[derive(Deserialize)]
+struct CreatePostQuery {
+    #[serde(default)]
+    auto_tag: bool,
+    #[serde(default)]
+    allow_duplicate: bool,
+}
+
+async fn create_post(req: HttpRequest, post: JsonBody<PostData>, query: web::Query<CreatePostQuery>, (db, deleted, last_id): (web::Data<PostsDb>, web::Data<DeletedPostsDb>, web::Data<LastPostIdDb>), (similarity, search): (web::Data<SimilarityDb>, web::Data<SearchDb>), notifier: web::Data<Option<Notifier>>, config: web::Data<Config>) -> impl Responder {
+    let mut post = post.into_inner();
+    if let Err(errors) = validate_post_data(&mut post, config.html_in_text) {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "errors": errors }));
+    }
+    let suggested_tags = config
+        .auto_tagging
+        .then(|| suggest_tags(&post.body, &config.auto_tag_stopwords, config.auto_tag_limit));
+    if let Some(suggested_tags) = suggested_tags.as_ref().filter(|_| query.auto_tag && post.tags.is_empty()) {
+        post.tags = suggested_tags.clone();
+    }
+    let mut db = db.acquire();
+    if config.reject_duplicate_posts && !query.allow_duplicate {
+        let hash = content_hash(&post.title, &post.body);
+        if let Some(existing) = db.values().filter(|existing| existing.content_hash == hash).min_by_key(|existing| existing.id) {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "error": "A post with the same title and body already exists",
+                "id": existing.id,
+            }));
+        }
+    }
+    let created = insert_post(&mut db, &deleted, &last_id, &similarity, &search, post, &config);
+    if let Some(notifier) = notifier.as_ref().as_ref().filter(|_| !created.draft) {
+        notifier.post_published(&created, &base_url(&req, &config), req.extensions().get::<RequestId>().map(|id| id.0.clone()));
+    }
+    let Some(suggested_tags) = suggested_tags else {
+        return HttpResponse::Created().finish();
+    };
+    HttpResponse::Created().json(serde_json::json!({
+        "id": created.id,
+        "tags": created.tags,
+        "suggested_tags": suggested_tags,
+    }))
+}
+
+async fn validate_post(post: JsonBody<PostData>, config: web::Data<Config>) -> impl Responder {
+    match validate_post_data(&mut post.into_inner(), config.html_in_text) {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "valid": true })),
+        Err(errors) => HttpResponse::Ok().json(serde_json::json!({ "valid": false, "errors": errors })),
+    }
+}
+
+#[derive(Serialize)]
+struct ImportError {
+    line: usize,
+    message: String,
+}
+
+#[derive(Default, Serialize)]
+struct ImportReport {
+    valid: bool,
+    lines: usize,
+    error_count: usize,
+    errors: Vec<ImportError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    imported: Option<usize>,
+}
+
+impl ImportReport {
+    fn record(&mut self, result: Result<Option<PostData>, String>, on_post: &mut impl FnMut(PostData)) {
+        self.lines += 1;
+        match result {
+            Ok(Some(post)) => on_post(post),
+            Ok(None) => {}
+            Err(message) => {
+                self.error_count += 1;
+                if self.errors.len() < MAX_REPORTED_IMPORT_ERRORS {
+                    self.errors.push(ImportError { line: self.lines, message });
+                }
+            }
+        }
+    }
+}
+
+fn parse_import_line(line: &[u8], config: &Config) -> Result<Option<PostData>, String> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => std::borrow::Cow::Borrowed(line),
+        Err(_) if config.lenient_import_utf8 => String::from_utf8_lossy(line),
+        Err(e) => return Err(format!("Invalid UTF-8 at byte {}", e.valid_up_to())),
+    };
+    let mut post: PostData = serde_json::from_str(&line).map_err(|e| format!("Invalid JSON: {}", e))?;
+    validate_post_data(&mut post, config.html_in_text).map_err(|errors| {
+        errors
+            .iter()
+            .map(|error| format!("{}: {}", error.field, error.message))
+            .collect::<Vec<_>>()
+            .join("; ")
+    })?;
+    Ok(Some(post))
+}
+
+async fn read_import(mut payload: web::Payload, config: &Config, mut on_post: impl FnMut(PostData)) -> Result<ImportReport, actix_web::Error> {
+    let mut report = ImportReport::default();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut finished = false;
+    let mut bom_checked = false;
+    let deadline = body_deadline(config.import_read_timeout);
+    while !finished {
+        match next_chunk(&mut payload, deadline).await? {
+            Some(chunk) => buffer.extend_from_slice(&chunk),
+            None => finished = true,
+        }
+        if !bom_checked && (buffer.len() >= UTF8_BOM.len() || finished) {
+            if buffer.starts_with(UTF8_BOM) {
+                buffer.drain(..UTF8_BOM.len());
+            }
+            bom_checked = true;
+        }
+        let mut consumed = 0;
+        while let Some(end) = buffer[consumed..].iter().position(|&b| b == b'\n').map(|i| consumed + i) {
+            report.record(parse_import_line(&buffer[consumed..end], config), &mut on_post);
+            consumed = end + 1;
+        }
+        buffer.drain(..consumed);
+        if finished && !buffer.is_empty() {
+            report.record(parse_import_line(&buffer, config), &mut on_post);
+        } else if buffer.len() > MAX_IMPORT_LINE_BYTES {
+            report.record(Err(format!("Line exceeds {} bytes", MAX_IMPORT_LINE_BYTES)), &mut on_post);
+            return Ok(report);
+        }
+    }
+    report.valid = report.error_count == 0;
+    Ok(report)
+}
+
+async fn validate_import(req: HttpRequest, payload: web::Payload, config: web::Data<Config>) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = require_admin(&req, &config) {
+        return Ok(response);
+    }
+    let report = read_import(payload, &config, |_| {}).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+async fn import_posts(req: HttpRequest, payload: web::Payload, (db, deleted, last_id): (web::Data<PostsDb>, web::Data<DeletedPostsDb>, web::Data<LastPostIdDb>), (similarity, search): (web::Data<SimilarityDb>, web::Data<SearchDb>), config: web::Data<Config>) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(response) = require_admin(&req, &config) {
+        return Ok(response);
+    }
+    let mut posts = Vec::new();
+    let mut report = read_import(payload, &config, |post| posts.push(post)).await?;
+    if !report.valid {
+        return Ok(HttpResponse::BadRequest().json(report));
+    }
+    let mut db = db.acquire();
+    report.imported = Some(posts.len());
+    for post in posts {
+        insert_post(&mut db, &deleted, &last_id, &similarity, &search, post, &config);
+    }
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    results: Vec<SearchHit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    id: u32,
+    slug: String,
+    title: String,
+}
+
+async fn search_posts(req: HttpRequest, query: web::Query<SearchQuery>, db: web::Data<PostsDb>, search: web::Data<SearchDb>, config: web::Data<Config>) -> impl Responder {
+    let terms: Vec<String> = tokenize(&query.q).collect();
+    if terms.is_empty() {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Query must contain at least one word" }));
+    }
+    let include_drafts = is_admin(&req, &config);
+    let db = db.acquire();
+    let search = search.acquire();
+    let ids: HashSet<u32> = if search.building && config.search_fallback_scan {
+        db.values()
+            .filter(|post| {
+                let post_terms = search_terms(post);
+                terms.iter().all(|term| post_terms.contains(term))
+            })
+            .map(|post| post.id)
+            .collect()
+    } else {
+        search.search(&terms)
+    };
+    let mut results: Vec<SearchHit> = ids
+        .into_iter()
+        .filter_map(|id| db.get(&id))
+        .filter(|post| include_drafts || !post.draft)
+        .map(|post| SearchHit { id: post.id, slug: post.slug.clone(), title: post.title.clone() })
+        .collect();
+    results.sort_by_key(|hit| hit.id);
+    HttpResponse::Ok().json(SearchResponse { results, warning: search.building.then_some("index building") })
+}
+
+async fn reindex_search(req: HttpRequest, db: web::Data<PostsDb>, search: web::Data<SearchDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    if !start_search_rebuild(&db, &search) {
+        return HttpResponse::Conflict().json(serde_json::json!({ "error": "The search index is already being rebuilt" }));
+    }
+    HttpResponse::Accepted().json(serde_json::json!({ "status": "building" }))
+}
+
+#[derive(Deserialize)]
+struct SimilarQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SimilarPost {
+    id: u32,
+    title: String,
+    score: f64,
+}
+
+async fn get_similar_posts(req: HttpRequest, post_id: web::Path<u32>, query: web::Query<SimilarQuery>, db: web::Data<PostsDb>, similarity: web::Data<SimilarityDb>, config: web::Data<Config>) -> impl Responder {
+    let post_id = post_id.into_inner();
+    let include_drafts = is_admin(&req, &config);
+    let db = db.acquire();
+    if !db.get(&post_id).is_some_and(|post| include_drafts || !post.draft) {
+        return HttpResponse::NotFound().finish();
+    }
+    let limit = query.limit.unwrap_or(config.similar_posts_limit).min(config.max_per_page);
+    let similar: Vec<SimilarPost> = similarity
+        .acquire()
+        .similar(post_id, usize::MAX)
+        .into_iter()
+        .filter_map(|(id, score)| db.get(&id).map(|post| (post, score)))
+        .filter(|(post, _)| include_drafts || !post.draft)
+        .take(limit)
+        .map(|(post, score)| SimilarPost { id: post.id, title: post.title.clone(), score })
+        .collect();
+    HttpResponse::Ok().json(similar)
+}
+
+#[derive(Deserialize)]
+struct PostPatch {
+    title: Option<String>,
+    body: Option<String>,
+    draft: Option<bool>,
+    tags: Option<Vec<String>>,
+    one_comment_per_author: Option<bool>,
+    comment_status: Option<CommentStatus>,
+    auto_approve_comments: Option<bool>,
+    language: Option<String>,
+}
+
+async fn update_post(req: HttpRequest, post_id: web::Path<u32>, patch: JsonBody<PostPatch>, db: web::Data<PostsDb>, (similarity, search): (web::Data<SimilarityDb>, web::Data<SearchDb>), notifier: web::Data<Option<Notifier>>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let patch = patch.into_inner();
+    let mut db = db.acquire();
+    let Some(post) = db.get_mut(&post_id.into_inner()) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let mut data = PostData {
+        title: patch.title.unwrap_or_else(|| post.title.clone()),
+        body: patch.body.unwrap_or_else(|| post.body.clone()),
+        draft: patch.draft.unwrap_or(post.draft),
+        tags: patch.tags.unwrap_or_else(|| post.tags.clone()),
+        one_comment_per_author: patch.one_comment_per_author.unwrap_or(post.one_comment_per_author),
+        language: patch.language.or_else(|| post.language.clone()),
+    };
+    if let Err(errors) = validate_post_data(&mut data, config.html_in_text) {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "errors": errors }));
+    }
+    let published = post.draft && !data.draft;
+    post.content_hash = content_hash(&data.title, &data.body);
+    post.excerpt = post_excerpt(&data.body, &config);
+    post.title = data.title;
+    post.body = data.body;
+    post.draft = data.draft;
+    post.tags = normalize_tags(data.tags);
+    post.one_comment_per_author = data.one_comment_per_author;
+    post.language = data.language;
+    post.comment_status = patch.comment_status.unwrap_or(post.comment_status);
+    post.auto_approve_comments = patch.auto_approve_comments.unwrap_or(post.auto_approve_comments);
+    post.updated_at = now();
+    similarity.acquire().update(post);
+    search.acquire().update(post);
+    if let Some(notifier) = notifier.as_ref().as_ref().filter(|_| published) {
+        notifier.post_published(post, &base_url(&req, &config), req.extensions().get::<RequestId>().map(|id| id.0.clone()));
+    }
+    HttpResponse::Ok().json(post.clone())
+}
+
+async fn get_form_token(req: HttpRequest, post_id: web::Path<u32>, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let post_id = post_id.into_inner();
+    let include_drafts = is_admin(&req, &config);
+    if !db.acquire().get(&post_id).is_some_and(|post| include_drafts || !post.draft) {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "token": issue_form_token(&config.csrf_secret, post_id),
+        "min_seconds": config.comment_min_seconds.unwrap_or(0),
+    }))
+}
+
+fn etag(body: &[u8]) -> String {
+    format!("\"{}\"", to_hex(&Sha256::digest(body)[..16]))
+}
+
+fn json_with_validators<T: Serialize>(response: &mut actix_web::HttpResponseBuilder, value: &T, last_modified: Option<u64>) -> HttpResponse {
+    let body = timed(Phase::Serialization, || serde_json::to_vec(value).unwrap_or_default());
+    response
+        .content_type("application/json")
+        .insert_header(("ETag", etag(&body)));
+    if let Some(last_modified) = last_modified {
+        let last_modified = UNIX_EPOCH + Duration::from_secs(last_modified);
+        response.insert_header(("Last-Modified", actix_web::http::header::HttpDate::from(last_modified)));
+    }
+    response.body(body)
+}
+
+async fn get_post(req: HttpRequest, post_id: web::Path<u32>, db: web::Data<PostsDb>, deleted: web::Data<DeletedPostsDb>, views: web::Data<ViewsDb>, config: web::Data<Config>) -> impl Responder {
+    let post_id = post_id.into_inner();
+    let include_drafts = is_admin(&req, &config);
+    let db = db.acquire();
+    if let Some(post) = db.get(&post_id).filter(|post| include_drafts || !post.draft) {
+        if req.method() == Method::GET {
+            *views.acquire().entry(post_id).or_default() += 1;
+        }
+        json_with_validators(&mut HttpResponse::Ok(), post, Some(post.updated_at))
+    } else if let Some(tombstone) = deleted.acquire().get(&post_id) {
+        HttpResponse::Gone().json(serde_json::json!({ "error": "This post has been deleted", "deleted_at": tombstone.deleted_at }))
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+struct DeletedPost {
+    post: Post,
+    comments: Vec<Comment>,
+    deleted_at: u64,
+}
+
+async fn delete_post(req: HttpRequest, post_id: web::Path<u32>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, deleted: web::Data<DeletedPostsDb>, (similarity, search): (web::Data<SimilarityDb>, web::Data<SearchDb>), config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let post_id = post_id.into_inner();
+    let mut db = db.acquire();
+    let Some(post) = db.remove(&post_id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let comments = comments_db.acquire().remove(&post_id).unwrap_or_default();
+    similarity.acquire().remove(post_id);
+    search.acquire().remove(post_id);
+    log::info!("Soft-deleted post {} ({:?}) with {} comments", post_id, post.title, comments.len());
+    deleted.acquire().insert(post_id, DeletedPost { post, comments, deleted_at: now() });
+    HttpResponse::NoContent().finish()
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    older_than: u64,
+    #[serde(default)]
+    confirm: bool,
+}
+
+async fn purge_deleted_posts(req: HttpRequest, query: web::Query<PurgeQuery>, deleted: web::Data<DeletedPostsDb>, (views, slug_redirects, comment_ids): (web::Data<ViewsDb>, web::Data<SlugRedirectsDb>, web::Data<CommentIdsDb>), config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let cutoff = now().saturating_sub(query.older_than.saturating_mul(24 * 60 * 60));
+    let mut deleted = deleted.acquire();
+    let mut post_ids: Vec<u32> = deleted.iter().filter(|(_, entry)| entry.deleted_at <= cutoff).map(|(id, _)| *id).collect();
+    post_ids.sort_unstable();
+    if !query.confirm {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Purging is permanent; repeat the request with confirm=true",
+            "matching": post_ids.len(),
+        }));
+    }
+    for post_id in &post_ids {
+        let entry = deleted.remove(post_id).unwrap();
+        log::info!("Purged soft-deleted post {} ({:?}) with {} comments, deleted at {}", post_id, entry.post.title, entry.comments.len(), entry.deleted_at);
+    }
+    let mut views = views.acquire();
+    let mut comment_ids = comment_ids.acquire();
+    for post_id in &post_ids {
+        views.remove(post_id);
+        comment_ids.remove(post_id);
+    }
+    slug_redirects.acquire().retain(|_, post_id| !post_ids.contains(post_id));
+    HttpResponse::Ok().json(serde_json::json!({ "purged": post_ids.len() }))
+}
+
+async fn restore_post(req: HttpRequest, post_id: web::Path<u32>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, deleted: web::Data<DeletedPostsDb>, (similarity, search): (web::Data<SimilarityDb>, web::Data<SearchDb>), config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let post_id = post_id.into_inner();
+    let mut db = db.acquire();
+    let Some(DeletedPost { mut post, comments, .. }) = deleted.acquire().remove(&post_id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    post.slug = unique_slug(&post.slug, |slug| db.values().any(|other| other.slug == slug));
+    similarity.acquire().update(&post);
+    search.acquire().update(&post);
+    if !comments.is_empty() {
+        comments_db.acquire().insert(post_id, comments);
+    }
+    db.insert(post_id, post.clone());
+    HttpResponse::Ok().json(post)
+}
+
+async fn get_post_by_slug(req: HttpRequest, slug: web::Path<String>, db: web::Data<PostsDb>, redirects: web::Data<SlugRedirectsDb>, config: web::Data<Config>) -> impl Responder {
+    let slug = slug.into_inner();
+    let include_drafts = is_admin(&req, &config);
+    let db = db.acquire();
+    if let Some(post) = db.values().find(|post| post.slug == slug) {
+        return match include_drafts || !post.draft {
+            true => HttpResponse::Ok().json(post.clone()),
+            false => HttpResponse::NotFound().finish(),
+        };
+    }
+    let current = redirects.acquire().get(&slug).and_then(|id| db.get(id)).map(|post| post.slug.clone());
+    match current {
+        Some(current) => HttpResponse::MovedPermanently()
+            .insert_header(("Location", format!("{}/api/posts/by-slug/{}", config.path_prefix, current)))
+            .finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn get_post_by_hash(req: HttpRequest, hash: web::Path<String>, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let hash = hash.into_inner().to_ascii_lowercase();
+    let include_drafts = is_admin(&req, &config);
+    let db = db.acquire();
+    let post = db
+        .values()
+        .filter(|post| post.content_hash == hash && (include_drafts || !post.draft))
+        .min_by_key(|post| post.id);
+    match post {
+        Some(post) => HttpResponse::Ok().json(post.clone()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Serialize)]
+struct SlugChange {
+    id: u32,
+    old: String,
+    new: String,
+}
+
+async fn regenerate_slugs(req: HttpRequest, db: web::Data<PostsDb>, redirects: web::Data<SlugRedirectsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let mut db = db.acquire();
+    let mut redirects = redirects.acquire();
+    let mut ids: Vec<u32> = db.keys().copied().collect();
+    ids.sort();
+
+    let mut assigned: HashSet<String> = HashSet::new();
+    let mut changed = Vec::new();
+    for id in &ids {
+        let post = db.get_mut(id).unwrap();
+        let slug = unique_slug(&slugify(&post.title), |slug| {
+            assigned.contains(slug) || redirects.get(slug).is_some_and(|owner| owner != id)
+        });
+        assigned.insert(slug.clone());
+        if slug != post.slug {
+            let old = std::mem::replace(&mut post.slug, slug.clone());
+            redirects.remove(&slug);
+            redirects.insert(old.clone(), *id);
+            changed.push(SlugChange { id: *id, old, new: slug });
+        }
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "changed": changed,
+        "unchanged": ids.len() - changed.len(),
+    }))
+}
+
+async fn get_post_outline(req: HttpRequest, post_id: web::Path<u32>, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let include_drafts = is_admin(&req, &config);
+    let post = db.acquire().get(&post_id.into_inner()).cloned();
+    match post.filter(|post| include_drafts || !post.draft) {
+        Some(post) => HttpResponse::Ok().json(post_outline(&post.body)),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+enum ByteRange {
+    Full,
+    Partial(usize, usize),
+    Unsatisfiable,
+}
+
+fn parse_byte_range(header: Option<&str>, len: usize) -> ByteRange {
+    let Some(spec) = header.and_then(|value| value.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return ByteRange::Full,
+        ("", suffix) => match suffix.parse::<usize>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<usize>() else {
+                return ByteRange::Full;
+            };
+            let end = match end {
+                "" => len.saturating_sub(1),
+                end => match end.parse::<usize>() {
+                    Ok(end) if end >= start => end.min(len.saturating_sub(1)),
+                    _ => return ByteRange::Full,
+                },
+            };
+            (start, end)
+        }
+    };
+    if len == 0 || start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+async fn get_post_body(req: HttpRequest, post_id: web::Path<u32>, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let include_drafts = is_admin(&req, &config);
+    let post = db.acquire().get(&post_id.into_inner()).cloned();
+    let Some(body) = post.filter(|post| include_drafts || !post.draft).map(|post| post.body) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let range = req.headers().get("Range").and_then(|value| value.to_str().ok());
+    match parse_byte_range(range, body.len()) {
+        ByteRange::Full => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .insert_header(("Accept-Ranges", "bytes"))
+            .body(body),
+        ByteRange::Partial(start, end) => HttpResponse::PartialContent()
+            .content_type("text/plain; charset=utf-8")
+            .insert_header(("Accept-Ranges", "bytes"))
+            .insert_header(("Content-Range", format!("bytes {}-{}/{}", start, end, body.len())))
+            .body(body.into_bytes()[start..=end].to_vec()),
+        ByteRange::Unsatisfiable => HttpResponse::RangeNotSatisfiable()
+            .insert_header(("Accept-Ranges", "bytes"))
+            .insert_header(("Content-Range", format!("bytes */{}", body.len())))
+            .finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct CommentListQuery {
+    mode: Option<String>,
+    replies: Option<usize>,
+}
+
+#[derive(Serialize, Clone)]
+struct CommentThread {
+    #[serde(flatten)]
+    comment: Comment,
+    replies: Vec<Comment>,
+    reply_count: usize,
+    more_replies: bool,
+}
+
+#[derive(Serialize)]
+struct Engagement {
+    views: u64,
+    likes: Option<u64>,
+    comment_count: usize,
+    last_comment_at: Option<u64>,
+    unique_commenters: usize,
+}
+
+async fn get_post_engagement(req: HttpRequest, post_id: web::Path<u32>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, views: web::Data<ViewsDb>, config: web::Data<Config>) -> impl Responder {
+    let post_id = post_id.into_inner();
+    let include_drafts = is_admin(&req, &config);
+    if !db.acquire().get(&post_id).is_some_and(|post| include_drafts || !post.draft) {
+        return HttpResponse::NotFound().finish();
+    }
+    let comments = visible_comments(&comments_db.acquire(), post_id, include_drafts);
+    let authors: HashSet<String> = comments.iter().filter_map(|comment| comment.author.as_ref()).map(|author| author.to_lowercase()).collect();
+    let anonymous = comments.iter().filter(|comment| comment.author.is_none()).count();
+    HttpResponse::Ok().json(Engagement {
+        views: views.acquire().get(&post_id).copied().unwrap_or_default(),
+        likes: None,
+        comment_count: comments.len(),
+        last_comment_at: comments.iter().map(|comment| comment.created_at).max(),
+        unique_commenters: authors.len() + anonymous,
+    })
+}
+
+fn visible_comments(comments_db: &HashMap<u32, Vec<Comment>>, post_id: u32, include_held: bool) -> Vec<Comment> {
+    comments_db
+        .get(&post_id)
+        .into_iter()
+        .flatten()
+        .filter(|comment| include_held || comment.approved)
+        .cloned()
+        .collect()
+}
+
+fn reply_map(comments: &[Comment]) -> HashMap<u32, Vec<&Comment>> {
+    let mut children: HashMap<u32, Vec<&Comment>> = HashMap::new();
+    for comment in comments {
+        if let Some(parent_id) = comment.parent_id {
+            children.entry(parent_id).or_default().push(comment);
+        }
+    }
+    children
+}
+
+fn descendants(children: &HashMap<u32, Vec<&Comment>>, root: u32) -> Vec<Comment> {
+    let mut result = Vec::new();
+    let mut stack: Vec<&Comment> = children.get(&root).into_iter().flatten().rev().copied().collect();
+    while let Some(comment) = stack.pop() {
+        result.push(comment.clone());
+        stack.extend(children.get(&comment.id).into_iter().flatten().rev());
+    }
+    result
+}
+
+async fn get_comments(req: HttpRequest, post_id: web::Path<u32>, pagination: web::Query<Pagination>, list: web::Query<CommentListQuery>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    let include_held = is_admin(&req, &config);
+    let comments = visible_comments(&comments_db.acquire(), post_id.into_inner(), include_held);
+    match list.mode.as_deref() {
+        None | Some("flat") => HttpResponse::Ok()
+            .insert_header(("X-Total-Count", comments.len()))
+            .json(pagination.apply(&comments, &config)),
+        Some("threaded") => {
+            let inline = list.replies.unwrap_or(config.inline_replies).min(config.max_per_page);
+            let top_level: Vec<&Comment> = comments.iter().filter(|comment| comment.parent_id.is_none()).collect();
+            let children = reply_map(&comments);
+            let threads: Vec<CommentThread> = pagination
+                .apply(&top_level, &config)
+                .into_iter()
+                .map(|comment| {
+                    let mut replies = descendants(&children, comment.id);
+                    let reply_count = replies.len();
+                    replies.truncate(inline);
+                    CommentThread { comment: comment.clone(), more_replies: reply_count > replies.len(), replies, reply_count }
+                })
+                .collect();
+            HttpResponse::Ok()
+                .insert_header(("X-Total-Count", top_level.len()))
+                .json(threads)
+        }
+        Some(other) => HttpResponse::BadRequest().json(serde_json::json!({ "error": format!("Unknown comment mode {:?}", other) })),
+    }
+}
+
+#[derive(Deserialize)]
+struct SubtreeQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+async fn get_comment_replies(req: HttpRequest, path: web::Path<(u32, u32)>, query: web::Query<SubtreeQuery>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    let (post_id, comment_id) = path.into_inner();
+    let include_held = is_admin(&req, &config);
+    let comments = visible_comments(&comments_db.acquire(), post_id, include_held);
+    if !comments.iter().any(|comment| comment.id == comment_id) {
+        return HttpResponse::NotFound().finish();
+    }
+    let replies = descendants(&reply_map(&comments), comment_id);
+    let limit = query.limit.unwrap_or(config.default_per_page).clamp(1, config.max_per_page);
+    let page: Vec<Comment> = replies.iter().skip(query.offset.unwrap_or(0)).take(limit).cloned().collect();
+    HttpResponse::Ok()
+        .insert_header(("X-Total-Count", replies.len()))
+        .json(page)
+}
+
+async fn get_comment_parent(req: HttpRequest, path: web::Path<(u32, u32)>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    let (post_id, comment_id) = path.into_inner();
+    let include_held = is_admin(&req, &config);
+    let comments = visible_comments(&comments_db.acquire(), post_id, include_held);
+    let Some(comment) = comments.iter().find(|comment| comment.id == comment_id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let Some(parent_id) = comment.parent_id else {
+        return HttpResponse::NoContent().finish();
+    };
+    match comments.iter().find(|comment| comment.id == parent_id) {
+        Some(parent) => HttpResponse::Ok().json(parent),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    let value = match value.chars().next() {
+        Some('=' | '+' | '-' | '@' | '\t' | '\r') => format!("'{}", value),
+        _ => value.to_string(),
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn comments_csv(comments: &[Comment], with_post_id: bool) -> String {
+    let mut csv = String::from(if with_post_id { "post_id,id,author,text,created_at\r\n" } else { "id,author,text,created_at\r\n" });
+    for comment in comments {
+        if with_post_id {
+            csv.push_str(&format!("{},", comment.post_id));
+        }
+        csv.push_str(&format!(
+            "{},{},{},{}\r\n",
+            comment.id,
+            csv_field(comment.author.as_deref().unwrap_or_default()),
+            csv_field(&comment.text),
+            comment.created_at
+        ));
+    }
+    csv
+}
+
+fn csv_download(filename: &str, csv: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+        .body(csv)
+}
+
+async fn get_post_comments_csv(req: HttpRequest, post_id: web::Path<u32>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    let post_id = post_id.into_inner();
+    let comments = visible_comments(&comments_db.acquire(), post_id, is_admin(&req, &config));
+    csv_download(&format!("post-{}-comments.csv", post_id), comments_csv(&comments, false))
+}
+
+async fn get_all_comments_csv(req: HttpRequest, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    let include_held = is_admin(&req, &config);
+    let comments_db = comments_db.acquire();
+    let mut post_ids: Vec<u32> = comments_db.keys().copied().collect();
+    post_ids.sort();
+    let comments: Vec<Comment> = post_ids
+        .into_iter()
+        .flat_map(|post_id| visible_comments(&comments_db, post_id, include_held))
+        .collect();
+    csv_download("comments.csv", comments_csv(&comments, true))
+}
+
+async fn approve_comment(req: HttpRequest, path: web::Path<(u32, u32)>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let (post_id, comment_id) = path.into_inner();
+    let mut comments_db = comments_db.acquire();
+    let comment = comments_db
+        .get_mut(&post_id)
+        .and_then(|comments| comments.iter_mut().find(|comment| comment.id == comment_id));
+    match comment {
+        Some(comment) => {
+            comment.approved = true;
+            HttpResponse::Ok().json(comment.clone())
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct BulkApproval {
+    ids: Option<Vec<(u32, u32)>>,
+    older_than_secs: Option<u64>,
+}
+
+async fn bulk_approve_comments(req: HttpRequest, approval: JsonBody<BulkApproval>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let approval = approval.into_inner();
+    let mut comments_db = comments_db.acquire();
+    let mut approved = 0;
+    let mut not_found = Vec::new();
+    match (approval.ids, approval.older_than_secs) {
+        (Some(ids), None) => {
+            for (post_id, comment_id) in ids {
+                let comment = comments_db
+                    .get_mut(&post_id)
+                    .and_then(|comments| comments.iter_mut().find(|comment| comment.id == comment_id));
+                match comment {
+                    Some(comment) if !comment.approved => {
+                        comment.approved = true;
+                        approved += 1;
+                    }
+                    Some(_) => {}
+                    None => not_found.push((post_id, comment_id)),
+                }
+            }
+        }
+        (None, Some(older_than_secs)) => {
+            let cutoff = now().saturating_sub(older_than_secs);
+            for comment in comments_db.values_mut().flatten() {
+                if !comment.approved && comment.created_at <= cutoff {
+                    comment.approved = true;
+                    approved += 1;
+                }
+            }
+        }
+        _ => {
+            return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Provide exactly one of ids or older_than_secs" }));
+        }
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "approved": approved, "not_found": not_found }))
+}
+
+struct QueuedComment {
+    ticket: u64,
+    comment: CommentData,
+    features: Features,
+    request_id: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum QueueStatus {
+    Pending,
+    Accepted { comment: Comment },
+    Rejected { reason: String },
+}
+
+#[derive(Clone)]
+struct CommentQueue {
+    sender: mpsc::Sender<QueuedComment>,
+    statuses: Arc<Mutex<BTreeMap<u64, QueueStatus>>>,
+    next_ticket: Arc<AtomicU64>,
+}
+
+impl CommentQueue {
+    fn start(capacity: usize, service: CommentService) -> CommentQueue {
+        let (sender, mut receiver) = mpsc::channel::<QueuedComment>(capacity);
+        let queue = CommentQueue {
+            sender,
+            statuses: Arc::new(Mutex::new(BTreeMap::new())),
+            next_ticket: Arc::new(AtomicU64::new(1)),
+        };
+        let statuses = queue.statuses.clone();
+        tokio::spawn(async move {
+            while let Some(queued) = receiver.recv().await {
+                let request_id = queued.request_id.as_deref().unwrap_or("-");
+                let status = match service.submit(queued.comment, &queued.features) {
+                    Ok(submission) => {
+                        log::info!("Queued comment {} accepted [request {}]", queued.ticket, request_id);
+                        QueueStatus::Accepted { comment: submission.comment() }
+                    }
+                    Err(error) => {
+                        log::info!("Queued comment {} rejected: {} [request {}]", queued.ticket, error.message(), request_id);
+                        QueueStatus::Rejected { reason: error.message() }
+                    }
+                };
+                statuses.acquire().insert(queued.ticket, status);
+            }
+        });
+        queue
+    }
+
+    fn enqueue(&self, comment: CommentData, features: Features, request_id: Option<String>) -> Option<u64> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut statuses = self.statuses.acquire();
+            statuses.insert(ticket, QueueStatus::Pending);
+            while statuses.len() > QUEUE_STATUS_RETENTION {
+                statuses.pop_first();
+            }
+        }
+        if self.sender.try_send(QueuedComment { ticket, comment, features, request_id }).is_err() {
+            self.statuses.acquire().remove(&ticket);
+            return None;
+        }
+        Some(ticket)
+    }
+}
+
+async fn create_comment(req: HttpRequest, comment: JsonBody<CommentData>, features: Features, service: web::Data<CommentService>, queue: web::Data<CommentQueue>, config: web::Data<Config>) -> impl Responder {
+    let mut comment = comment.into_inner();
+    comment.client_id = client_id(&req, &config);
+    if features.enabled("comment_queue", config.queued_comment_posts.contains(comment.post_id)) {
+        let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+        return match queue.enqueue(comment, features, request_id) {
+            Some(ticket) => {
+                let status_url = format!("{}/api/comments/queue/{}", config.path_prefix, ticket);
+                HttpResponse::Accepted()
+                    .insert_header(("Location", status_url.clone()))
+                    .json(serde_json::json!({ "ticket": ticket, "status_url": status_url }))
+            }
+            None => unavailable_response(&req, &config, StatusCode::SERVICE_UNAVAILABLE, 5, "Comment queue is full"),
+        };
+    }
+
+    match service.submit(comment, &features) {
+        Ok(Submission::Created(_)) => HttpResponse::Created().finish(),
+        Ok(Submission::Replayed(comment)) => HttpResponse::Ok().json(comment),
+        Err(error) => error.response(),
+    }
+}
+
+#[derive(Serialize)]
+struct HighlightedComment {
+    #[serde(flatten)]
+    comment: Comment,
+    post_title: String,
+}
+
+async fn get_random_comments(query: web::Query<LimitQuery>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    let limit = query.limit.unwrap_or(3).min(config.max_per_page);
+    let db = db.acquire();
+    let comments_db = comments_db.acquire();
+    let eligible: Vec<(&Comment, &Post)> = comments_db
+        .values()
+        .flatten()
+        .filter(|comment| comment.approved)
+        .filter_map(|comment| db.get(&comment.post_id).filter(|post| !post.draft).map(|post| (comment, post)))
+        .collect();
+    let highlights: Vec<HighlightedComment> = eligible
+        .choose_multiple(&mut rand::thread_rng(), limit)
+        .map(|(comment, post)| HighlightedComment { comment: (*comment).clone(), post_title: post.title.clone() })
+        .collect();
+    HttpResponse::Ok().json(highlights)
+}
+
+async fn get_queued_comment(ticket: web::Path<u64>, queue: web::Data<CommentQueue>) -> impl Responder {
+    match queue.statuses.acquire().get(&ticket.into_inner()) {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn post_page(req: HttpRequest, post_id: web::Path<u32>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, views: web::Data<ViewsDb>, config: web::Data<Config>) -> impl Responder {
+    let post_id = post_id.into_inner();
+    let Some(post) = db.acquire().get(&post_id).filter(|post| !post.draft).cloned() else {
+        return HttpResponse::NotFound().finish();
+    };
+    *views.acquire().entry(post_id).or_default() += 1;
+    let comments: Vec<Comment> = comments_db
+        .acquire()
+        .get(&post_id)
+        .into_iter()
+        .flatten()
+        .filter(|comment| comment.approved)
+        .cloned()
+        .collect();
+
+    let csrf_token = req
+        .cookie(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|token| csrf_token_is_valid(&config.csrf_secret, token))
+        .unwrap_or_else(|| issue_csrf_token(&config.csrf_secret));
+
+    let comments_html: String = comments
+        .iter()
+        .map(|comment| format!(
+            r#"<div class="p-4 bg-white border border-black rounded"><p class="text-sm text-gray-600">{}</p><p>{}</p></div>"#,
+            escape_html(comment.author.as_deref().unwrap_or("Anonymous")),
+            if config.linkify_comments { linkify_comment(&comment.text) } else { escape_html(&comment.text) }
+        ))
+        .collect();
+    let comment_form = if comments_closed(&post, &config) {
+        r#"<p class="mt-8 text-gray-600">Comments are closed.</p>"#.to_string()
+    } else {
+        format!(
+            r#"<h2 class="text-2xl font-semibold mt-8 mb-2">Add Comment</h2>
+            <form method="post" action="{prefix}/posts/{id}/comments" class="space-y-2">
+                <input type="hidden" name="csrf_token" value="{csrf_token}"/>
+                <input type="hidden" name="nonce" value="{nonce}"/>
+                <input type="hidden" name="form_token" value="{form_token}"/>
+                <input type="text" name="author" placeholder="Your name (optional)" class="p-2 border border-gray-300 rounded w-full"/>
+                <textarea name="text" placeholder="Your comment" class="p-2 border border-gray-300 rounded w-full h-40"></textarea>
+                <button type="submit" class="px-4 py-2 bg-black text-white rounded">Add Comment</button>
+            </form>"#,
+            prefix = config.path_prefix,
+            id = post.id,
+            csrf_token = csrf_token,
+            nonce = to_hex(&rand::random::<[u8; 16]>()),
+            form_token = issue_form_token(&config.csrf_secret, post.id),
+        )
+    };
+    let html = format!(
+        r#"<!DOCTYPE html>
+    <html>
+    <head>
+        <title>{title}</title>
+        <script src="https://cdn.tailwindcss.com"></script>
+    </head>
+    <body class="text-gray-900">
+        <div class="container mx-auto p-4">
+            <h1 class="text-4xl font-bold mb-4">{title}</h1>
+            <div class="text-lg mb-4 space-y-2">{body}</div>
+            <h2 class="text-2xl font-semibold mb-2">Comments</h2>
+            <div class="space-y-4">{comments}</div>
+            {comment_form}
+        </div>
+    </body>
+    </html>
+    "#,
+        title = escape_html(&post.title),
+        body = render_markdown(&post.body),
+        comments = comments_html,
+        comment_form = comment_form,
+    );
+
+    let cookie = Cookie::build(CSRF_COOKIE, csrf_token)
+        .path(format!("{}/posts", config.path_prefix))
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish();
+    HttpResponse::Ok().content_type("text/html").cookie(cookie).body(html)
+}
+
+async fn submit_comment_form(req: HttpRequest, post_id: web::Path<u32>, form: FormBody<CommentForm>, features: Features, service: web::Data<CommentService>, config: web::Data<Config>) -> impl Responder {
+    let form = form.into_inner();
+    if features.enabled("csrf", config.csrf_protection) {
+        let cookie_token = req.cookie(CSRF_COOKIE).map(|cookie| cookie.value().to_string());
+        let valid = cookie_token.is_some_and(|token| {
+            token == form.csrf_token && csrf_token_is_valid(&config.csrf_secret, &token)
+        });
+        if !valid {
+            return HttpResponse::Forbidden().body("Invalid or missing CSRF token");
+        }
+    }
+
+    let post_id = post_id.into_inner();
+    let comment = CommentData {
+        post_id,
+        text: form.text,
+        parent_id: None,
+        author: form.author,
+        client_id: client_id(&req, &config),
+        nonce: form.nonce,
+        form_token: form.form_token,
+    };
+    if let Err(error) = service.submit(comment, &features) {
+        return HttpResponse::build(error.status()).body(error.message());
+    }
+    HttpResponse::SeeOther()
+        .insert_header(("Location", format!("{}/posts/{}", config.path_prefix, post_id)))
+        .finish()
+}
+
+fn base_url(req: &HttpRequest, config: &Config) -> String {
+    match &config.base_url {
+        Some(url) => url.clone(),
+        None => {
+            let connection = req.connection_info();
+            format!("{}://{}{}", connection.scheme(), connection.host(), config.path_prefix)
+        }
+    }
+}
+
+fn rss_feed(base: &str, title: &str, feed_path: &str, posts: &HashMap<u32, Post>, include: impl Fn(&Post) -> bool) -> String {
+    let mut posts: Vec<&Post> = posts.values().filter(|post| !post.draft && include(post)).collect();
+    posts.sort_by(|a, b| newest_first(a, b));
+    posts.truncate(FEED_ITEMS);
+    let items: String = posts
+        .iter()
+        .map(|post| {
+            let link = format!("{}/posts/{}", base, post.id);
+            let published = actix_web::http::header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(post.created_at));
+            format!(
+                "<item><title>{}</title><link>{}</link><guid isPermaLink=\"true\">{}</guid><pubDate>{}</pubDate><description>{}</description></item>",
+                escape_html(&post.title),
+                escape_html(&link),
+                escape_html(&link),
+                published,
+                escape_html(&post.excerpt)
+            )
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom"><channel><title>{title}</title><link>{base}/</link><description>{title}</description><atom:link href="{base}{feed_path}" rel="self" type="application/rss+xml"/>{items}</channel></rss>
+"#,
+        title = escape_html(title),
+        base = escape_html(base),
+        feed_path = escape_html(feed_path),
+        items = items
+    )
+}
+
+async fn get_feed(req: HttpRequest, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let feed = rss_feed(&base_url(&req, &config), FEED_TITLE, "/feed.xml", &db.acquire(), |_| true);
+    HttpResponse::Ok().content_type("application/rss+xml; charset=utf-8").body(feed)
+}
+
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+async fn get_tag_feed(req: HttpRequest, tag: web::Path<String>, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let tag = tag.into_inner().trim().to_lowercase();
+    let db = db.acquire();
+    if !db.values().any(|post| !post.draft && post.tags.contains(&tag)) {
+        return HttpResponse::NotFound().finish();
+    }
+    let title = format!("{}: {}", FEED_TITLE, tag);
+    let feed_path = format!("/feed/tag/{}.xml", encode_path_segment(&tag));
+    let feed = rss_feed(&base_url(&req, &config), &title, &feed_path, &db, |post| post.tags.contains(&tag));
+    HttpResponse::Ok().content_type("application/rss+xml; charset=utf-8").body(feed)
+}
+
+async fn get_feeds_opml(req: HttpRequest, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    let base = escape_html(&base_url(&req, &config));
+    let title = escape_html(FEED_TITLE);
+    let tags: BTreeSet<String> = db
+        .acquire()
+        .values()
+        .filter(|post| !post.draft)
+        .flat_map(|post| post.tags.iter().cloned())
+        .collect();
+    let tag_outlines: String = tags
+        .iter()
+        .map(|tag| {
+            let text = escape_html(&format!("{}: {}", FEED_TITLE, tag));
+            format!(r#"<outline type="rss" text="{text}" title="{text}" xmlUrl="{base}/feed/tag/{}.xml" htmlUrl="{base}/"/>"#, encode_path_segment(tag))
+        })
+        .collect();
+    let opml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<opml version="2.0"><head><title>{title}</title></head><body><outline type="rss" text="{title}" title="{title}" xmlUrl="{base}/feed.xml" htmlUrl="{base}/"/>{tag_outlines}</body></opml>
+"#
+    );
+    HttpResponse::Ok().content_type("text/x-opml; charset=utf-8").body(opml)
+}
+
+#[derive(Clone)]
+struct Notifier {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    secret: Vec<u8>,
+    subscribers: SubscribersDb,
+    confirmations: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Notifier {
+    fn message(&self, email: &str, subject: &str, body: String, request_id: Option<&str>) -> Result<Message, String> {
+        let to = email.parse::<Mailbox>().map_err(|e| e.to_string())?;
+        let mut builder = Message::builder().from(self.from.clone()).to(to).subject(subject);
+        if let Some(request_id) = request_id {
+            let name = mail_header::HeaderName::new_from_ascii_str("X-Request-Id");
+            builder = builder.raw_header(mail_header::HeaderValue::new(name, request_id.to_string()));
+        }
+        builder.body(body).map_err(|e| e.to_string())
+    }
+
+    async fn send(&self, message: Result<Message, String>) -> Result<(), String> {
+        self.mailer.send(message?).await.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn post_published(&self, post: &Post, base: &str, request_id: Option<String>) {
+        let recipients: Vec<String> = self.subscribers.acquire().iter().cloned().collect();
+        if recipients.is_empty() {
+            return;
+        }
+        let log_id = request_id.clone().unwrap_or_else(|| "-".to_string());
+        log::info!("Notifying {} subscribers of post {} [request {}]", recipients.len(), post.id, log_id);
+        let notifier = self.clone();
+        let subject = format!("New post: {}", post.title);
+        let text = format!("{}\n\n{}\n\nRead it at {}/posts/{}\n", post.title, post.excerpt, base, post.id);
+        let base = base.to_string();
+        tokio::spawn(async move {
+            for email in recipients {
+                let token = subscription_token(&notifier.secret, "unsubscribe", &email);
+                let body = format!("{}\nUnsubscribe: {}/api/unsubscribe?token={}\n", text, base, token);
+                let message = notifier.message(&email, &subject, body, request_id.as_deref());
+                if let Err(e) = notifier.send(message).await {
+                    log::warn!("Failed to notify {} of a new post: {} [request {}]", email, e, log_id);
+                }
+            }
+        });
+    }
+
+    // Returns false when a confirmation was already sent to the address within the last hour, so
+    // the endpoint cannot be used to flood someone's mailbox.
+    fn request_confirmation(&self, email: &str, base: &str, request_id: Option<String>) -> bool {
+        let sent_at = now();
+        {
+            let mut confirmations = self.confirmations.acquire();
+            confirmations.retain(|_, sent| sent_at.saturating_sub(*sent) < CONFIRMATION_RESEND_SECS);
+            if confirmations.contains_key(email) {
+                return false;
+            }
+            confirmations.insert(email.to_string(), sent_at);
+        }
+        let log_id = request_id.clone().unwrap_or_else(|| "-".to_string());
+        log::info!("Sending subscription confirmation to {} [request {}]", email, log_id);
+        let token = subscription_token(&self.secret, "confirm", email);
+        let body = format!(
+            "Someone asked to receive an email whenever a new post is published on {}.\n\nConfirm the subscription at {}/api/subscribe/confirm?token={}\n\nIf this was not you, ignore this email and nothing will be sent.\n",
+            base, base, token
+        );
+        let message = self.message(email, "Confirm your subscription", body, request_id.as_deref());
+        let (notifier, email) = (self.clone(), email.to_string());
+        tokio::spawn(async move {
+            if let Err(e) = notifier.send(message).await {
+                log::warn!("Failed to send a subscription confirmation to {}: {} [request {}]", email, e, log_id);
+            }
+        });
+        true
+    }
+}
+
+#[derive(Deserialize)]
+struct Subscription {
+    email: String,
+}
+
+async fn subscribe(req: HttpRequest, subscription: JsonBody<Subscription>, notifier: web::Data<Option<Notifier>>, config: web::Data<Config>) -> impl Responder {
+    let Some(notifier) = notifier.as_ref() else {
+        return HttpResponse::NotFound().finish();
+    };
+    let email = subscription.into_inner().email.trim().to_lowercase();
+    if email.parse::<Address>().is_err() {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "errors": [FieldError { field: "email", message: "Email must be a valid address".to_string() }],
+        }));
+    }
+    if notifier.subscribers.acquire().contains(&email) {
+        return HttpResponse::Ok().finish();
+    }
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+    let status = match notifier.request_confirmation(&email, &base_url(&req, &config), request_id) {
+        true => "confirmation sent",
+        false => "confirmation already sent",
+    };
+    HttpResponse::Accepted().json(serde_json::json!({ "status": status }))
+}
+
+#[derive(Deserialize)]
+struct ConfirmQuery {
+    token: String,
+}
+
+async fn confirm_subscription(query: web::Query<ConfirmQuery>, notifier: web::Data<Option<Notifier>>) -> impl Responder {
+    let Some(notifier) = notifier.as_ref() else {
+        return HttpResponse::NotFound().finish();
+    };
+    let Some(email) = subscription_email(&notifier.secret, "confirm", &query.token) else {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Invalid confirmation token" }));
+    };
+    notifier.confirmations.acquire().remove(&email);
+    notifier.subscribers.acquire().insert(email.clone());
+    HttpResponse::Ok().json(serde_json::json!({ "subscribed": email }))
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeQuery {
+    token: String,
+}
+
+async fn unsubscribe(query: web::Query<UnsubscribeQuery>, notifier: web::Data<Option<Notifier>>) -> impl Responder {
+    let Some(notifier) = notifier.as_ref() else {
+        return HttpResponse::NotFound().finish();
+    };
+    let Some(email) = subscription_email(&notifier.secret, "unsubscribe", &query.token) else {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Invalid unsubscribe token" }));
+    };
+    notifier.subscribers.acquire().remove(&email);
+    HttpResponse::Ok().json(serde_json::json!({ "unsubscribed": email }))
+}
+
+#[derive(Serialize)]
+struct PostStorage {
+    id: u32,
+    title: String,
+    bytes: usize,
+    comment_bytes: usize,
+}
+
+#[derive(Serialize)]
+struct StorageReport {
+    total_bytes: usize,
+    post_bytes: usize,
+    comment_bytes: usize,
+    post_count: usize,
+    comment_count: usize,
+    average_post_bytes: usize,
+    largest_posts: Vec<PostStorage>,
+}
+
+#[derive(Deserialize)]
+struct StorageQuery {
+    limit: Option<usize>,
+}
+
+fn serialized_size<T: Serialize>(value: &T) -> usize {
+    serde_json::to_vec(value).map_or(0, |bytes| bytes.len())
+}
+
+async fn storage_usage(req: HttpRequest, query: web::Query<StorageQuery>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let db = db.acquire();
+    let comments_db = comments_db.acquire();
+
+    let mut posts: Vec<PostStorage> = db
+        .values()
+        .map(|post| PostStorage {
+            id: post.id,
+            title: post.title.clone(),
+            bytes: serialized_size(post),
+            comment_bytes: comments_db.get(&post.id).map_or(0, |comments| comments.iter().map(serialized_size).sum()),
+        })
+        .collect();
+    let post_bytes: usize = posts.iter().map(|post| post.bytes).sum();
+    let comment_bytes: usize = comments_db.values().flatten().map(serialized_size).sum();
+    let comment_count = comments_db.values().map(Vec::len).sum();
+    let post_count = posts.len();
+
+    posts.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.id.cmp(&b.id)));
+    posts.truncate(query.limit.unwrap_or(10));
+
+    HttpResponse::Ok().json(StorageReport {
+        total_bytes: post_bytes + comment_bytes,
+        post_bytes,
+        comment_bytes,
+        post_count,
+        comment_count,
+        average_post_bytes: post_bytes.checked_div(post_count).unwrap_or(0),
+        largest_posts: posts,
+    })
+}
+
+#[derive(Deserialize)]
+struct NoCommentsQuery {
+    older_than: Option<u64>,
+}
+
+async fn posts_without_comments(req: HttpRequest, pagination: web::Query<Pagination>, query: web::Query<NoCommentsQuery>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let cutoff = query.older_than.map(|days| now().saturating_sub(days.saturating_mul(24 * 60 * 60)));
+    let db = db.acquire();
+    let comments_db = comments_db.acquire();
+    let mut posts: Vec<Post> = db
+        .values()
+        .filter(|post| comments_db.get(&post.id).is_none_or(Vec::is_empty))
+        .filter(|post| cutoff.is_none_or(|cutoff| post.created_at < cutoff))
+        .cloned()
+        .collect();
+    posts.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+    HttpResponse::Ok()
+        .insert_header(("X-Total-Count", posts.len()))
+        .json(pagination.apply(&posts, &config))
+}
+
+#[derive(Serialize)]
+struct UntaggedPost {
+    #[serde(flatten)]
+    post: Post,
+    suggested_tags: Vec<String>,
+}
+
+async fn untagged_posts(req: HttpRequest, pagination: web::Query<Pagination>, db: web::Data<PostsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let db = db.acquire();
+    let mut posts: Vec<&Post> = db.values().filter(|post| post.tags.is_empty()).collect();
+    posts.sort_by_key(|post| post.id);
+    let page: Vec<UntaggedPost> = pagination
+        .apply(&posts, &config)
+        .into_iter()
+        .map(|post| UntaggedPost {
+            post: post.clone(),
+            suggested_tags: suggest_tags(&post.body, &config.auto_tag_stopwords, config.auto_tag_limit),
+        })
+        .collect();
+    HttpResponse::Ok()
+        .insert_header(("X-Total-Count", posts.len()))
+        .json(page)
+}
+
+#[derive(Serialize)]
+struct OrphanedComments {
+    post_id: u32,
+    comments: Vec<Comment>,
+}
+
+fn orphaned_post_ids(posts: &HashMap<u32, Post>, comments: &HashMap<u32, Vec<Comment>>) -> Vec<u32> {
+    let mut ids: Vec<u32> = comments.keys().filter(|id| !posts.contains_key(id)).copied().collect();
+    ids.sort();
+    ids
+}
+
+async fn list_orphans(req: HttpRequest, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let db = db.acquire();
+    let comments_db = comments_db.acquire();
+    let orphans: Vec<OrphanedComments> = orphaned_post_ids(&db, &comments_db)
+        .into_iter()
+        .map(|post_id| OrphanedComments { post_id, comments: comments_db[&post_id].clone() })
+        .collect();
+    HttpResponse::Ok().json(orphans)
+}
+
+async fn cleanup_orphans(req: HttpRequest, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let db = db.acquire();
+    let mut comments_db = comments_db.acquire();
+    let post_ids = orphaned_post_ids(&db, &comments_db);
+    let removed_comments: usize = post_ids
+        .iter()
+        .filter_map(|post_id| comments_db.remove(post_id))
+        .map(|comments| comments.len())
+        .sum();
+    log::info!("Removed {} orphaned comments for posts {:?}", removed_comments, post_ids);
+    HttpResponse::Ok().json(serde_json::json!({ "post_ids": post_ids, "removed_comments": removed_comments }))
+}
+
+struct Snapshot {
+    name: String,
+    taken_at: u64,
+    posts: BTreeMap<u32, Post>,
+    comments: BTreeMap<(u32, u32), Comment>,
+}
+
+impl Snapshot {
+    fn capture(name: String, posts_db: &PostsDb, comments_db: &CommentsDb) -> Snapshot {
+        let posts = posts_db.acquire().iter().map(|(id, post)| (*id, post.clone())).collect();
+        let comments = comments_db
+            .acquire()
+            .values()
+            .flatten()
+            .map(|comment| ((comment.post_id, comment.id), comment.clone()))
+            .collect();
+        Snapshot { name, taken_at: now(), posts, comments }
+    }
+}
+
+#[derive(Deserialize)]
+struct SnapshotRequest {
+    name: String,
+}
+
+async fn create_snapshot(req: HttpRequest, request: JsonBody<SnapshotRequest>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, snapshots: web::Data<SnapshotsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    if config.max_snapshots == 0 {
+        return HttpResponse::Forbidden().json(serde_json::json!({ "error": "Snapshots are disabled; set MAX_SNAPSHOTS to enable them" }));
+    }
+    let name = request.into_inner().name.trim().to_string();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "Snapshot name must not be empty" }));
+    }
+    let snapshot = Snapshot::capture(name, &db, &comments_db);
+    let summary = serde_json::json!({
+        "name": snapshot.name,
+        "taken_at": snapshot.taken_at,
+        "posts": snapshot.posts.len(),
+        "comments": snapshot.comments.len(),
+    });
+    let mut snapshots = snapshots.acquire();
+    snapshots.retain(|existing| existing.name != snapshot.name);
+    snapshots.push_back(snapshot);
+    while snapshots.len() > config.max_snapshots {
+        snapshots.pop_front();
+    }
+    HttpResponse::Created().json(summary)
+}
+
+#[derive(Serialize)]
+struct Modified<T> {
+    before: T,
+    after: T,
+}
+
+#[derive(Serialize)]
+struct ChangeSet<T> {
+    added: Vec<T>,
+    removed: Vec<T>,
+    modified: Vec<Modified<T>>,
+}
+
+fn diff_maps<K: Ord, T: Clone + PartialEq>(before: &BTreeMap<K, T>, after: &BTreeMap<K, T>) -> ChangeSet<T> {
+    let mut changes = ChangeSet { added: Vec::new(), removed: Vec::new(), modified: Vec::new() };
+    for (key, old) in before {
+        match after.get(key) {
+            None => changes.removed.push(old.clone()),
+            Some(new) if new != old => changes.modified.push(Modified { before: old.clone(), after: new.clone() }),
+            Some(_) => {}
+        }
+    }
+    changes.added = after.iter().filter(|(key, _)| !before.contains_key(key)).map(|(_, new)| new.clone()).collect();
+    changes
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    from: String,
+}
+
+async fn diff_snapshot(req: HttpRequest, query: web::Query<DiffQuery>, db: web::Data<PostsDb>, comments_db: web::Data<CommentsDb>, snapshots: web::Data<SnapshotsDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let snapshots = snapshots.acquire();
+    let Some(from) = snapshots.iter().find(|snapshot| snapshot.name == query.from) else {
+        return HttpResponse::NotFound().json(serde_json::json!({ "error": format!("No snapshot named {:?}", query.from) }));
+    };
+    let current = Snapshot::capture(String::new(), &db, &comments_db);
+    HttpResponse::Ok().json(serde_json::json!({
+        "from": from.name,
+        "taken_at": from.taken_at,
+        "posts": diff_maps(&from.posts, &current.posts),
+        "comments": diff_maps(&from.comments, &current.comments),
+    }))
+}
+
+fn prune_drafts(posts_db: &PostsDb, comments_db: &CommentsDb, similarity_db: &SimilarityDb, search_db: &SearchDb, max_age_secs: u64, dry_run: bool) -> Vec<u32> {
+    let cutoff = now().saturating_sub(max_age_secs);
+    let mut db = posts_db.acquire();
+    let stale: Vec<u32> = db
+        .values()
+        .filter(|post| post.draft && post.updated_at < cutoff)
+        .map(|post| post.id)
+        .collect();
+    for id in &stale {
+        if dry_run {
+            log::info!("Would prune draft {} ({:?}), untouched since {}", id, db[id].title, db[id].updated_at);
+            continue;
+        }
+        if let Some(post) = db.remove(id) {
+            log::info!("Pruned draft {} ({:?}), untouched since {}", id, post.title, post.updated_at);
+            comments_db.acquire().remove(id);
+            similarity_db.acquire().remove(*id);
+            search_db.acquire().remove(*id);
+        }
+    }
+    stale
+}
+
+trait RouteSink {
+    fn add<F, Args>(&mut self, method: Method, path: &'static str, handler: F)
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static;
+}
+
+impl RouteSink for web::ServiceConfig {
+    fn add<F, Args>(&mut self, method: Method, path: &'static str, handler: F)
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        self.route(path, web::method(method).to(handler));
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct RouteInfo {
+    method: String,
+    path: String,
+}
+
+#[derive(Default, Clone)]
+struct RouteTable(Vec<RouteInfo>);
+
+impl RouteSink for RouteTable {
+    fn add<F, Args>(&mut self, method: Method, path: &'static str, _handler: F)
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        self.0.push(RouteInfo { method: method.to_string(), path: path.to_string() });
+    }
+}
+
+impl RouteTable {
+    fn build(config: &Config) -> RouteTable {
+        let mut table = RouteTable::default();
+        register_routes(&mut table, config);
+        for route in &mut table.0 {
+            route.path.insert_str(0, &config.path_prefix);
+        }
+        table
+    }
+}
+
+fn register_routes<R: RouteSink>(routes: &mut R, config: &Config) {
+    routes.add(Method::GET, "/", index);
+    routes.add(Method::GET, "/posts/{id}", post_page);
+    routes.add(Method::POST, "/posts/{id}/comments", submit_comment_form);
+    routes.add(Method::GET, "/feed.xml", get_feed);
+    routes.add(Method::GET, "/feed/tag/{tag}.xml", get_tag_feed);
+    routes.add(Method::GET, "/feeds.opml", get_feeds_opml);
+    routes.add(Method::POST, "/api/subscribe", subscribe);
+    routes.add(Method::GET, "/api/subscribe/confirm", confirm_subscription);
+    routes.add(Method::GET, "/api/unsubscribe", unsubscribe);
+    routes.add(Method::GET, "/api/routes", list_routes);
+    routes.add(Method::GET, "/api/schema", get_schema);
+    routes.add(Method::GET, "/api/posts", get_posts);
+    routes.add(Method::HEAD, "/api/posts", get_posts);
+    routes.add(Method::POST, "/api/posts", create_post);
+    routes.add(Method::POST, "/api/posts/validate", validate_post);
+    routes.add(Method::GET, "/api/posts/latest", get_latest_posts);
+    routes.add(Method::GET, "/api/posts/recently-edited", get_recently_edited_posts);
+    routes.add(Method::GET, "/api/posts/active", get_active_posts);
+    routes.add(Method::GET, "/api/posts/by-slug/{slug}", get_post_by_slug);
+    routes.add(Method::GET, "/api/posts/by-hash/{hash}", get_post_by_hash);
+    routes.add(Method::GET, "/api/posts/by-tag", get_posts_by_tag);
+    routes.add(Method::GET, "/api/posts/search", search_posts);
+    routes.add(Method::GET, "/api/posts/{id}", get_post);
+    routes.add(Method::HEAD, "/api/posts/{id}", get_post);
+    routes.add(Method::PATCH, "/api/posts/{id}", update_post);
+    routes.add(Method::DELETE, "/api/posts/{id}", delete_post);
+    routes.add(Method::POST, "/api/posts/{id}/restore", restore_post);
+    routes.add(Method::GET, "/api/posts/{id}/body", get_post_body);
+    routes.add(Method::GET, "/api/posts/{id}/similar", get_similar_posts);
+    routes.add(Method::GET, "/api/posts/{id}/outline", get_post_outline);
+    routes.add(Method::GET, "/api/posts/{id}/engagement", get_post_engagement);
+    routes.add(Method::GET, "/api/posts/{id}/form-token", get_form_token);
+    routes.add(Method::GET, "/api/posts/{id}/comments", get_comments);
+    routes.add(Method::GET, "/api/posts/{id}/comments.csv", get_post_comments_csv);
+    routes.add(Method::GET, "/api/posts/{post_id}/comments/{comment_id}/replies", get_comment_replies);
+    routes.add(Method::GET, "/api/posts/{post_id}/comments/{comment_id}/parent", get_comment_parent);
+    routes.add(Method::POST, "/api/posts/{post_id}/comments/{comment_id}/approve", approve_comment);
+    routes.add(Method::POST, "/api/comments", create_comment);
+    routes.add(Method::POST, "/api/comments/approve", bulk_approve_comments);
+    routes.add(Method::GET, "/api/comments.csv", get_all_comments_csv);
+    routes.add(Method::GET, "/api/comments/random", get_random_comments);
+    if config.dev_mode || !matches!(config.queued_comment_posts, PostSelection::None) {
+        routes.add(Method::GET, "/api/comments/queue/{ticket}", get_queued_comment);
+    }
+    routes.add(Method::POST, "/api/import", import_posts);
+    routes.add(Method::POST, "/api/import/validate", validate_import);
+    routes.add(Method::GET, "/api/admin/storage", storage_usage);
+    routes.add(Method::GET, "/api/admin/posts/no-comments", posts_without_comments);
+    routes.add(Method::GET, "/api/admin/posts/untagged", untagged_posts);
+    routes.add(Method::GET, "/api/admin/traffic", traffic_report);
+    routes.add(Method::DELETE, "/api/admin/traffic", reset_traffic);
+    routes.add(Method::GET, "/api/admin/latency", latency_report);
+    routes.add(Method::GET, "/api/admin/orphans", list_orphans);
+    routes.add(Method::POST, "/api/admin/orphans/cleanup", cleanup_orphans);
+    routes.add(Method::POST, "/api/admin/purge", purge_deleted_posts);
+    routes.add(Method::POST, "/api/admin/snapshot", create_snapshot);
+    routes.add(Method::GET, "/api/admin/diff", diff_snapshot);
+    routes.add(Method::POST, "/api/admin/slugs/regenerate", regenerate_slugs);
+    routes.add(Method::POST, "/api/admin/search/reindex", reindex_search);
+}
+
+async fn get_schema() -> impl Responder {
+    let output = || SchemaSettings::default().for_serialize().into_generator();
+    let input = || SchemaSettings::default().for_deserialize().into_generator();
+    HttpResponse::Ok().json(serde_json::json!({
+        "post": output().into_root_schema_for::<Post>(),
+        "comment": output().into_root_schema_for::<Comment>(),
+        "create_post": input().into_root_schema_for::<PostData>(),
+        "create_comment": input().into_root_schema_for::<CommentData>(),
+    }))
+}
+
+async fn list_routes(table: web::Data<RouteTable>) -> impl Responder {
+    HttpResponse::Ok().json(&table.0)
+}
+
+fn load_tls_config(cert_path: &str, key_path: &str) -> std::io::Result<rustls::ServerConfig> {
+    let open = |path: &str| {
+        std::fs::File::open(path)
+            .map(std::io::BufReader::new)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path, e)))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)?
+        .ok_or_else(|| std::io::Error::other(format!("{}: no private key found", key_path)))?;
+    rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(std::io::Error::other)
+}
+
+#[derive(Default, Serialize)]
+struct TrafficCounts {
+    reads: u64,
+    writes: u64,
+}
+
+impl TrafficCounts {
+    fn record(&mut self, read: bool) {
+        if read {
+            self.reads += 1;
+        } else {
+            self.writes += 1;
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TrafficStats {
+    since: u64,
+    #[serde(flatten)]
+    total: TrafficCounts,
+    routes: BTreeMap<String, TrafficCounts>,
+}
+
+impl TrafficStats {
+    fn new() -> TrafficStats {
+        TrafficStats { since: now(), total: TrafficCounts::default(), routes: BTreeMap::new() }
+    }
+}
+
+struct LatencyWindows {
+    window: Duration,
+    max_samples: usize,
+    routes: HashMap<String, VecDeque<(Instant, Duration)>>,
+}
+
+#[derive(Serialize)]
+struct LatencyPercentiles {
+    count: usize,
+    p50_ms: f64,
+    p90_ms: f64,
+    p99_ms: f64,
+}
+
+impl LatencyWindows {
+    fn record(&mut self, route: String, latency: Duration) {
+        let now = Instant::now();
+        let samples = self.routes.entry(route).or_default();
+        samples.push_back((now, latency));
+        while samples.len() > self.max_samples || samples.front().is_some_and(|(at, _)| now.duration_since(*at) > self.window) {
+            samples.pop_front();
+        }
+    }
+
+    fn report(&mut self) -> BTreeMap<String, LatencyPercentiles> {
+        let now = Instant::now();
+        let window = self.window;
+        self.routes.retain(|_, samples| {
+            samples.retain(|(at, _)| now.duration_since(*at) <= window);
+            !samples.is_empty()
+        });
+        self.routes
+            .iter()
+            .map(|(route, samples)| {
+                let mut latencies: Vec<Duration> = samples.iter().map(|(_, latency)| *latency).collect();
+                latencies.sort_unstable();
+                let percentile = |p: f64| {
+                    let rank = ((p * latencies.len() as f64).ceil() as usize).clamp(1, latencies.len());
+                    millis(latencies[rank - 1])
+                };
+                let percentiles = LatencyPercentiles { count: latencies.len(), p50_ms: percentile(0.5), p90_ms: percentile(0.9), p99_ms: percentile(0.99) };
+                (route.clone(), percentiles)
+            })
+            .collect()
+    }
+}
+
+async fn count_traffic(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let traffic = req.app_data::<web::Data<TrafficDb>>().cloned();
+    let latency = req.app_data::<web::Data<LatencyDb>>().cloned();
+    let read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let method = req.method().clone();
+    let started = Instant::now();
+    let res = next.call(req).await?;
+    let elapsed = started.elapsed();
+    let route = res.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+    if let Some(latency) = latency {
+        latency.acquire().record(format!("{} {}", method, route), elapsed);
+    }
+    if let Some(traffic) = traffic {
+        let mut traffic = traffic.acquire();
+        traffic.total.record(read);
+        traffic.routes.entry(route).or_default().record(read);
+    }
+    Ok(res)
+}
+
+async fn traffic_report(req: HttpRequest, traffic: web::Data<TrafficDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let traffic = traffic.acquire();
+    let total = traffic.total.reads + traffic.total.writes;
+    let mut report = serde_json::to_value(&*traffic).unwrap_or_default();
+    report["read_ratio"] = serde_json::json!((total > 0).then(|| traffic.total.reads as f64 / total as f64));
+    HttpResponse::Ok().json(report)
+}
+
+async fn reset_traffic(req: HttpRequest, traffic: web::Data<TrafficDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    *traffic.acquire() = TrafficStats::new();
+    HttpResponse::NoContent().finish()
+}
+
+#[derive(Deserialize)]
+struct LatencyQuery {
+    #[serde(default)]
+    reset: bool,
+}
+
+async fn latency_report(req: HttpRequest, query: web::Query<LatencyQuery>, latency: web::Data<LatencyDb>, config: web::Data<Config>) -> impl Responder {
+    if let Err(response) = require_admin(&req, &config) {
+        return response;
+    }
+    let mut latency = latency.acquire();
+    let routes = latency.report();
+    if query.reset {
+        latency.routes.clear();
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "window_secs": latency.window.as_secs(),
+        "routes": routes,
+    }))
+}
+
+async fn strict_transport_security(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let hsts = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|config| config.hsts.clone())
+        .filter(|_| req.connection_info().scheme() == "https");
+    let mut res = next.call(req).await?;
+    if let Some(value) = hsts.and_then(|value| HeaderValue::from_str(&value).ok()) {
+        res.headers_mut().insert(actix_web::http::header::STRICT_TRANSPORT_SECURITY, value);
+    }
+    Ok(res)
+}
+
+#[derive(Clone)]
+struct RequestId(String);
+
+async fn propagate_request_id(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let header = req.app_data::<web::Data<Config>>().and_then(|config| config.request_id_header.clone());
+    let Some(header) = header else {
+        return next.call(req).await;
+    };
+    let id = req
+        .headers()
+        .get(&header)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH && value.chars().all(|c| c.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| to_hex(&rand::random::<[u8; 16]>()));
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let mut res = next.call(req).await?;
+    if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(header.as_bytes()), HeaderValue::from_str(&id)) {
+        res.headers_mut().insert(name, value);
+    }
+    Ok(res)
+}
+
+#[derive(Deserialize)]
+struct EnvelopeQuery {
+    envelope: Option<bool>,
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+#[derive(Deserialize)]
+struct DebugTimingQuery {
+    #[serde(default)]
+    debug_timing: bool,
+}
+
+async fn debug_timing(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let enabled = web::Query::<DebugTimingQuery>::from_query(req.query_string()).is_ok_and(|query| query.debug_timing);
+    if !enabled {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+    let times = Rc::new(PhaseTimes::default());
+    let started = Instant::now();
+    let res = PHASE_TIMES.scope(times.clone(), next.call(req)).await?.map_into_boxed_body();
+    let is_json = res
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !is_json {
+        return Ok(res);
+    }
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let bytes = actix_web::body::to_bytes(body).await.map_err(actix_web::error::ErrorInternalServerError)?;
+    if serde_json::from_slice::<serde::de::IgnoredAny>(&bytes).is_err() {
+        return Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(bytes))));
+    }
+    let timing = format!(
+        "\"_timing\":{}",
+        serde_json::json!({
+            "lock_ms": millis(times.lock.get()),
+            "serialization_ms": millis(times.serialization.get()),
+            "rendering_ms": millis(times.rendering.get()),
+            "total_ms": millis(started.elapsed()),
+        })
+    );
+    let trimmed = bytes.trim_ascii();
+    let body = match trimmed.strip_prefix(b"{").and_then(|object| object.strip_suffix(b"}")) {
+        Some(fields) if fields.trim_ascii().is_empty() => format!("{{{}}}", timing).into_bytes(),
+        Some(fields) => [b"{", fields, b",", timing.as_bytes(), b"}"].concat(),
+        None => [b"{\"data\":", trimmed, b",", timing.as_bytes(), b"}"].concat(),
+    };
+    let mut res = res.set_body(BoxBody::new(body.clone()));
+    if res.headers().contains_key("ETag") {
+        res.headers_mut().insert(actix_web::http::header::ETAG, HeaderValue::from_str(&etag(&body)).unwrap());
+    }
+    Ok(ServiceResponse::new(req, res))
+}
+
+async fn response_envelope(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let default = req.app_data::<web::Data<Config>>().is_some_and(|config| config.response_envelope);
+    let enabled = web::Query::<EnvelopeQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.envelope)
+        .unwrap_or(default);
+    let res = next.call(req).await?.map_into_boxed_body();
+    let is_json = res
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !enabled || !res.status().is_success() || !is_json {
+        return Ok(res);
+    }
+    let total = res
+        .headers()
+        .get("X-Total-Count")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let bytes = actix_web::body::to_bytes(body).await.map_err(actix_web::error::ErrorInternalServerError)?;
+    if serde_json::from_slice::<serde::de::IgnoredAny>(&bytes).is_err() {
+        return Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(bytes))));
+    }
+    let mut envelope = b"{\"data\":".to_vec();
+    envelope.extend_from_slice(&bytes);
+    if let Some(total) = total {
+        envelope.extend_from_slice(format!(",\"meta\":{{\"total\":{}}}", total).as_bytes());
+    }
+    envelope.push(b'}');
+    let mut res = res.set_body(BoxBody::new(envelope.clone()));
+    if res.headers().contains_key("ETag") {
+        res.headers_mut().insert(actix_web::http::header::ETAG, HeaderValue::from_str(&etag(&envelope)).unwrap());
+    }
+    Ok(ServiceResponse::new(req, res))
+}
+
+async fn redirect_to_prefix(config: web::Data<Config>) -> impl Responder {
+    HttpResponse::MovedPermanently()
+        .insert_header(("Location", format!("{}/", config.path_prefix)))
+        .finish()
+}
+
+async fn redirect_to_https(req: HttpRequest, config: web::Data<Config>) -> impl Responder {
+    let connection = req.connection_info();
+    let host = connection.host();
+    let hostname = match host.strip_prefix('[') {
+        Some(rest) => format!("[{}]", rest.split(']').next().unwrap_or_default()),
+        None => host.split(':').next().unwrap_or_default().to_string(),
+    };
+    let port = match config.bind_addr.port() {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    HttpResponse::MovedPermanently()
+        .insert_header(("Location", format!("https://{}{}{}", hostname, port, path)))
+        .finish()
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let config = Config::from_env().map_err(std::io::Error::other)?;
+    if config.dev_mode {
+        log::warn!("DEV_MODE is enabled; X-Feature-Flags overrides are honoured. Never enable this in production.");
+    }
+    let posts_db: PostsDb = Arc::new(Mutex::new(HashMap::new()));
+    let comments_db: CommentsDb = Arc::new(Mutex::new(HashMap::new()));
+    let deleted_posts_db: DeletedPostsDb = Arc::new(Mutex::new(HashMap::new()));
+    let last_post_id_db: LastPostIdDb = Arc::new(Mutex::new(0));
+    let comment_ids_db: CommentIdsDb = Arc::new(Mutex::new(HashMap::new()));
+    let comment_service = CommentService {
+        config: config.clone(),
+        posts_db: posts_db.clone(),
+        comments_db: comments_db.clone(),
+        recent_hashes: Arc::new(Mutex::new(RecentHashes::default())),
+        recent_nonces: Arc::new(Mutex::new(RecentNonces::default())),
+        deleted_posts: deleted_posts_db.clone(),
+        last_comment_ids: comment_ids_db.clone(),
+    };
+    let comment_queue = CommentQueue::start(config.comment_queue_capacity, comment_service.clone());
+    let similarity_db: SimilarityDb = Arc::new(Mutex::new(SimilarityIndex::default()));
+    let search_db: SearchDb = Arc::new(Mutex::new(SearchIndex::default()));
+    start_search_rebuild(&posts_db, &search_db);
+
+    if let Some(max_age_days) = config.draft_max_age_days {
+        let (posts_db, comments_db, similarity_db, search_db) = (posts_db.clone(), comments_db.clone(), similarity_db.clone(), search_db.clone());
+        let dry_run = config.draft_prune_dry_run;
+        let mut interval = tokio::time::interval(Duration::from_secs(config.draft_prune_interval_secs));
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                prune_drafts(&posts_db, &comments_db, &similarity_db, &search_db, max_age_days * 24 * 60 * 60, dry_run);
+            }
+        });
+    }
+
+    let slug_redirects_db: SlugRedirectsDb = Arc::new(Mutex::new(HashMap::new()));
+    let snapshots_db: SnapshotsDb = Arc::new(Mutex::new(VecDeque::new()));
+    let traffic_db: TrafficDb = Arc::new(Mutex::new(TrafficStats::new()));
+    let views_db: ViewsDb = Arc::new(Mutex::new(HashMap::new()));
+    let latency_db: LatencyDb = Arc::new(Mutex::new(LatencyWindows {
+        window: Duration::from_secs(config.latency_window_secs),
+        max_samples: config.latency_max_samples,
+        routes: HashMap::new(),
+    }));
+    let notifier = match &config.smtp {
+        Some(smtp) => Some(Notifier {
+            mailer: smtp.transport().map_err(std::io::Error::other)?,
+            from: smtp.from.clone(),
+            secret: config.csrf_secret.clone(),
+            subscribers: Arc::new(Mutex::new(BTreeSet::new())),
+            confirmations: Arc::new(Mutex::new(HashMap::new())),
+        }),
+        None => None,
+    };
+    let route_table = RouteTable::build(&config);
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls_config(cert, key)?),
+        _ => None,
+    };
+    let (bind_addr, http_port) = (config.bind_addr, config.http_port);
+    let redirect = config.redirect_http.then(|| {
+        let config = config.clone();
+        HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(config.clone()))
+                .default_service(web::to(redirect_to_https))
+        })
+    });
+
+    let server = HttpServer::new(move || {
+        let security_headers = config
+            .security_headers
+            .iter()
+            .fold(DefaultHeaders::new(), |headers, (name, value)| headers.add((*name, value.as_str())));
+        App::new()
+            .wrap(from_fn(response_envelope))
+            .wrap(Condition::new(config.dev_mode, from_fn(debug_timing)))
+            .wrap(from_fn(count_traffic))
+            .wrap(security_headers)
+            .wrap(from_fn(strict_transport_security))
+            .wrap(from_fn(propagate_request_id))
+            .app_data(web::Data::new(route_table.clone()))
+            .app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(posts_db.clone()))
+            .app_data(web::Data::new(comments_db.clone()))
+            .app_data(web::Data::new(deleted_posts_db.clone()))
+            .app_data(web::Data::new(last_post_id_db.clone()))
+            .app_data(web::Data::new(comment_ids_db.clone()))
+            .app_data(web::Data::new(comment_service.clone()))
+            .app_data(web::Data::new(comment_queue.clone()))
+            .app_data(web::Data::new(similarity_db.clone()))
+            .app_data(web::Data::new(search_db.clone()))
+            .app_data(web::Data::new(slug_redirects_db.clone()))
+            .app_data(web::Data::new(snapshots_db.clone()))
+            .app_data(web::Data::new(traffic_db.clone()))
+            .app_data(web::Data::new(latency_db.clone()))
+            .app_data(web::Data::new(notifier.clone()))
+            .app_data(web::Data::new(views_db.clone()))
+            .configure(|cfg| {
+                if !config.path_prefix.is_empty() {
+                    cfg.route(&config.path_prefix, web::get().to(redirect_to_prefix));
+                }
+            })
+            .service(web::scope(&config.path_prefix).configure(|cfg| register_routes(cfg, &config)))
+    });
+    let server = match tls {
+        Some(tls) => server.bind_rustls_0_23(bind_addr, tls)?,
+        None => server.bind(bind_addr)?,
+    }
+    .run();
+
+    match redirect {
+        Some(redirect) => {
+            let redirect = redirect.bind(SocketAddr::new(bind_addr.ip(), http_port))?.run();
+            futures_util::future::try_join(server, redirect).await.map(|_| ())
+        }
+        None => server.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    struct TestServer {
+        addr: SocketAddr,
+        handle: actix_web::dev::ServerHandle,
+        posts_db: PostsDb,
+        comments_db: CommentsDb,
+    }
+
+    // Every setting is spelled out so the tests do not depend on the environment they run in.
+    fn test_config() -> Config {
+        Config {
+            csrf_protection: true,
+            csrf_secret: b"test-secret".to_vec(),
+            admin_token: None,
+            default_per_page: 20,
+            max_per_page: 100,
+            comment_queue_capacity: 1000,
+            queued_comment_posts: PostSelection::None,
+            similar_posts_limit: 5,
+            inline_replies: 3,
+            draft_max_age_days: None,
+            draft_prune_interval_secs: 3600,
+            draft_prune_dry_run: true,
+            unavailable_json_template: DEFAULT_UNAVAILABLE_JSON_TEMPLATE.to_string(),
+            unavailable_html_template: DEFAULT_UNAVAILABLE_HTML_TEMPLATE.to_string(),
+            title_collator: None,
+            comment_dedup: DedupMode::Off,
+            comment_dedup_window_secs: 3600,
+            comment_dedup_capacity: 10_000,
+            dev_mode: false,
+            lenient_json: false,
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            tls_cert: None,
+            tls_key: None,
+            redirect_http: false,
+            http_port: 80,
+            search_fallback_scan: true,
+            security_headers: Vec::new(),
+            comments_close_after_days: None,
+            path_prefix: String::new(),
+            trusted_proxies: HashSet::new(),
+            lenient_import_utf8: false,
+            max_snapshots: 5,
+            comment_nonce_window_secs: 600,
+            comment_min_seconds: None,
+            response_envelope: false,
+            auto_tagging: false,
+            auto_tag_limit: 3,
+            auto_tag_stopwords: HashSet::new(),
+            hsts: None,
+            moderate_comments: false,
+            html_in_text: HtmlPolicy::Allow,
+            request_id_header: None,
+            excerpt_mode: ExcerptMode::Chars,
+            excerpt_length: 200,
+            latency_window_secs: 300,
+            latency_max_samples: 10_000,
+            reject_duplicate_posts: false,
+            base_url: None,
+            empty_index_message: String::new(),
+            smtp: None,
+            body_read_timeout: Some(Duration::from_secs(30)),
+            import_read_timeout: Some(Duration::from_secs(600)),
+            linkify_comments: true,
+            comment_max_links: None,
+            flag_excess_links: false,
+        }
+    }
+
+    fn start_server(config: Config) -> TestServer {
+        let posts_db: PostsDb = Arc::new(Mutex::new(HashMap::new()));
+        let comments_db: CommentsDb = Arc::new(Mutex::new(HashMap::new()));
+        let deleted_posts_db: DeletedPostsDb = Arc::new(Mutex::new(HashMap::new()));
+        let last_post_id_db: LastPostIdDb = Arc::new(Mutex::new(0));
+        let similarity_db: SimilarityDb = Arc::new(Mutex::new(SimilarityIndex::default()));
+        let search_db: SearchDb = Arc::new(Mutex::new(SearchIndex::default()));
+        let comment_service = CommentService {
+            config: config.clone(),
+            posts_db: posts_db.clone(),
+            comments_db: comments_db.clone(),
+            recent_hashes: Arc::new(Mutex::new(RecentHashes::default())),
+            recent_nonces: Arc::new(Mutex::new(RecentNonces::default())),
+            deleted_posts: deleted_posts_db.clone(),
+            last_comment_ids: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let comment_queue = CommentQueue::start(config.comment_queue_capacity, comment_service.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = {
+            let posts_db = posts_db.clone();
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(config.clone()))
+                    .app_data(web::Data::new(posts_db.clone()))
+                    .app_data(web::Data::new(deleted_posts_db.clone()))
+                    .app_data(web::Data::new(last_post_id_db.clone()))
+                    .app_data(web::Data::new(similarity_db.clone()))
+                    .app_data(web::Data::new(search_db.clone()))
+                    .app_data(web::Data::new(comment_service.clone()))
+                    .app_data(web::Data::new(comment_queue.clone()))
+                    .app_data(web::Data::new(None::<Notifier>))
+                    .configure(|cfg| register_routes(cfg, &config))
+            })
+            .workers(8)
+            .listen(listener)
+            .unwrap()
+            .run()
+        };
+        let handle = server.handle();
+        tokio::spawn(server);
+        TestServer { addr, handle, posts_db, comments_db }
+    }
+
+    async fn post_json(addr: SocketAddr, path: &str, body: &str) -> (u16, String) {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path,
+            addr,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response.split(' ').nth(1).and_then(|status| status.parse().ok()).unwrap_or(0);
+        let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+        (status, body)
+    }
+
+    async fn post_concurrently(addr: SocketAddr, path: &str, bodies: Vec<String>) -> Vec<(u16, String)> {
+        let barrier = Arc::new(tokio::sync::Barrier::new(bodies.len()));
+        let tasks: Vec<_> = bodies
+            .into_iter()
+            .map(|body| {
+                let (path, barrier) = (path.to_string(), barrier.clone());
+                tokio::spawn(async move {
+                    barrier.wait().await;
+                    post_json(addr, &path, &body).await
+                })
+            })
+            .collect();
+        let mut responses = Vec::new();
+        for task in tasks {
+            responses.push(task.await.unwrap());
+        }
+        responses
+    }
+
+    async fn create_concurrently(config: Config, query: &str, requests: usize) -> (Vec<u16>, usize) {
+        let server = start_server(config);
+        let body = r#"{"title":"Same title","body":"Same body"}"#.to_string();
+        let responses = post_concurrently(server.addr, &format!("/api/posts{}", query), vec![body; requests]).await;
+        server.handle.stop(true).await;
+        let created = server.posts_db.acquire().len();
+        (responses.into_iter().map(|(status, _)| status).collect(), created)
+    }
+
+    #[actix_web::test]
+    async fn concurrent_identical_posts_are_created_once() {
+        let mut config = test_config();
+        config.reject_duplicate_posts = true;
+        let (statuses, created) = create_concurrently(config, "", 32).await;
+        assert_eq!(created, 1);
+        assert_eq!(statuses.iter().filter(|status| **status == 201).count(), 1);
+        assert_eq!(statuses.iter().filter(|status| **status == 409).count(), 31);
+    }
+
+    #[actix_web::test]
+    async fn allow_duplicate_creates_every_identical_post() {
+        let mut config = test_config();
+        config.reject_duplicate_posts = true;
+        let (statuses, created) = create_concurrently(config, "?allow_duplicate=true", 32).await;
+        assert_eq!(created, 32);
+        assert!(statuses.iter().all(|status| *status == 201));
+    }
+
+    #[actix_web::test]
+    async fn concurrent_comments_get_unique_increasing_ids() {
+        let server = start_server(test_config());
+        let (status, _) = post_json(server.addr, "/api/posts", r#"{"title":"Busy post","body":"Discuss"}"#).await;
+        assert_eq!(status, 201);
+        let bodies = (0..64).map(|i| format!(r#"{{"post_id":1,"text":"Comment number {}"}}"#, i)).collect();
+        let responses = post_concurrently(server.addr, "/api/comments", bodies).await;
+        server.handle.stop(true).await;
+        assert!(responses.iter().all(|(status, _)| *status == 201));
+        let comments_db = server.comments_db.acquire();
+        let ids: Vec<u32> = comments_db[&1].iter().map(|comment| comment.id).collect();
+        assert_eq!(ids.len(), 64);
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 64);
+    }
+
+    #[test]
+    fn slash_separated_attributes_are_treated_as_html() {
+        for text in ["<img/src=x/onerror=alert(1)>", "<svg/onload=alert(1)>", "a<svg/onload=alert(1)>b"] {
+            assert!(strip_html_tags(text).1, "{:?} was not detected", text);
+            assert!(HtmlPolicy::Reject.apply("title", text.to_string()).is_err());
+            let stripped = HtmlPolicy::Strip.apply("title", text.to_string()).unwrap();
+            assert!(!stripped.contains('<'), "{:?} was stripped to {:?}", text, stripped);
+        }
+    }
+
+    #[test]
+    fn plain_text_survives_html_stripping() {
+        for text in ["Tom &amp; Jerry", "3 < 4 and 5 > 2", "AT&T", "line one\nline two", "quotes \" and '"] {
+            assert_eq!(strip_html_tags(text), (text.to_string(), false));
+        }
+        assert_eq!(strip_html_tags("Bold <b>move</b><!-- note -->"), ("Bold move".to_string(), true));
+    }
+}